@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE device_info
+(
+    device_id        INTEGER PRIMARY KEY,
+    protocol_version INT      NOT NULL,
+    firmware_version TEXT     NOT NULL,
+    hardware_model   TEXT     NOT NULL,
+    capabilities     TEXT     NOT NULL DEFAULT '[]',
+    last_hello_at    DATETIME NOT NULL,
+
+    FOREIGN KEY (device_id) REFERENCES room (room_id) ON DELETE CASCADE
+);
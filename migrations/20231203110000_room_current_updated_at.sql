@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE room ADD COLUMN current_updated_at DATETIME;
@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE device_info ADD COLUMN boot_id TEXT;
+ALTER TABLE device_info ADD COLUMN last_seq INTEGER;
+ALTER TABLE device_info ADD COLUMN seq_gaps INT NOT NULL DEFAULT 0;
+ALTER TABLE device_info ADD COLUMN seq_duplicates INT NOT NULL DEFAULT 0;
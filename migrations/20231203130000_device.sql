@@ -0,0 +1,41 @@
+-- Add migration script here
+CREATE TABLE device
+(
+    device_id  INTEGER PRIMARY KEY,
+    owner_id   INT      NOT NULL,
+    room_id    INT,
+    name       TEXT     NOT NULL DEFAULT '',
+    created_at DATETIME NOT NULL DEFAULT (datetime('now')),
+
+    FOREIGN KEY (owner_id) REFERENCES user (user_id),
+    FOREIGN KEY (room_id) REFERENCES room (room_id) ON DELETE SET NULL
+);
+
+-- Every existing room was created with its device id as the primary key.
+INSERT INTO device(device_id, owner_id, room_id)
+SELECT room_id, owner_id, room_id
+FROM room;
+
+ALTER TABLE room_history ADD COLUMN device_id INT REFERENCES device (device_id) ON DELETE SET NULL;
+UPDATE room_history SET device_id = room_id;
+
+CREATE TABLE device_info_new
+(
+    device_id        INTEGER PRIMARY KEY,
+    protocol_version INT      NOT NULL,
+    firmware_version TEXT     NOT NULL,
+    hardware_model   TEXT     NOT NULL,
+    capabilities     TEXT     NOT NULL DEFAULT '[]',
+    last_hello_at    DATETIME NOT NULL,
+    boot_id          TEXT,
+    last_seq         INTEGER,
+    seq_gaps         INT      NOT NULL DEFAULT 0,
+    seq_duplicates   INT      NOT NULL DEFAULT 0,
+
+    FOREIGN KEY (device_id) REFERENCES device (device_id) ON DELETE CASCADE
+);
+INSERT INTO device_info_new SELECT device_id, protocol_version, firmware_version, hardware_model, capabilities,
+                                   last_hello_at, boot_id, last_seq, seq_gaps, seq_duplicates
+                            FROM device_info;
+DROP TABLE device_info;
+ALTER TABLE device_info_new RENAME TO device_info;
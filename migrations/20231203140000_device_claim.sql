@@ -0,0 +1,9 @@
+-- Add migration script here
+ALTER TABLE device ADD COLUMN secret_hash TEXT;
+
+CREATE TABLE device_claim
+(
+    device_id  INTEGER PRIMARY KEY,
+    code       TEXT     NOT NULL UNIQUE,
+    expires_at DATETIME NOT NULL
+);
@@ -0,0 +1,16 @@
+-- Add migration script here
+ALTER TABLE room ADD COLUMN aggregation TEXT NOT NULL DEFAULT 'mean' CHECK (
+    aggregation IN ('mean', 'median', 'min', 'max', 'primary')
+);
+ALTER TABLE room ADD COLUMN primary_device_id INT REFERENCES device (device_id) ON DELETE SET NULL;
+
+ALTER TABLE device ADD COLUMN last_temperature REAL;
+ALTER TABLE device ADD COLUMN last_humidity REAL;
+ALTER TABLE device ADD COLUMN last_watthour REAL;
+ALTER TABLE device ADD COLUMN last_reading_at DATETIME;
+
+UPDATE device
+SET last_temperature = (SELECT current_temperature FROM room WHERE room.room_id = device.room_id),
+    last_humidity    = (SELECT current_humidity FROM room WHERE room.room_id = device.room_id),
+    last_watthour    = (SELECT current_watthour FROM room WHERE room.room_id = device.room_id),
+    last_reading_at  = (SELECT current_updated_at FROM room WHERE room.room_id = device.room_id);
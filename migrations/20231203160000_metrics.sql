@@ -0,0 +1,46 @@
+-- Add migration script here
+CREATE TABLE metric_type
+(
+    metric_id   INTEGER PRIMARY KEY AUTOINCREMENT,
+    name        TEXT NOT NULL UNIQUE,
+    unit        TEXT NOT NULL,
+    description TEXT NOT NULL DEFAULT ''
+);
+
+INSERT INTO metric_type(name, unit, description)
+VALUES ('temperature', '°C', 'Air temperature'),
+       ('humidity', '%', 'Relative humidity'),
+       ('watthour', 'Wh', 'Energy usage'),
+       ('co2', 'ppm', 'Carbon dioxide concentration'),
+       ('pm25', 'µg/m³', 'Fine particulate matter'),
+       ('lux', 'lx', 'Illuminance'),
+       ('pressure', 'hPa', 'Air pressure');
+
+CREATE TABLE metric_sample
+(
+    room_id    INT      NOT NULL,
+    device_id  INT,
+    metric_id  INT      NOT NULL,
+    value      REAL     NOT NULL,
+    created_at DATETIME NOT NULL,
+
+    FOREIGN KEY (room_id) REFERENCES room (room_id) ON DELETE CASCADE,
+    FOREIGN KEY (device_id) REFERENCES device (device_id) ON DELETE SET NULL,
+    FOREIGN KEY (metric_id) REFERENCES metric_type (metric_id)
+);
+CREATE INDEX metric_sample_room_metric ON metric_sample (room_id, metric_id, created_at);
+
+INSERT INTO metric_sample(room_id, device_id, metric_id, value, created_at)
+SELECT h.room_id, h.device_id, m.metric_id, h.temperature, h.created_at
+FROM room_history h, metric_type m
+WHERE m.name = 'temperature';
+
+INSERT INTO metric_sample(room_id, device_id, metric_id, value, created_at)
+SELECT h.room_id, h.device_id, m.metric_id, h.humidity, h.created_at
+FROM room_history h, metric_type m
+WHERE m.name = 'humidity';
+
+INSERT INTO metric_sample(room_id, device_id, metric_id, value, created_at)
+SELECT h.room_id, h.device_id, m.metric_id, h.watthour, h.created_at
+FROM room_history h, metric_type m
+WHERE m.name = 'watthour';
@@ -0,0 +1,29 @@
+-- Add migration script here
+ALTER TABLE device_info ADD COLUMN malformed_frames INT NOT NULL DEFAULT 0;
+
+CREATE TABLE device_diagnostics
+(
+    diagnostics_id  INTEGER PRIMARY KEY AUTOINCREMENT,
+    device_id       INT      NOT NULL,
+    rssi            INT,
+    uptime_ms       INTEGER,
+    free_heap       INTEGER,
+    battery_voltage REAL,
+    reset_reason    TEXT,
+    created_at      DATETIME NOT NULL,
+
+    FOREIGN KEY (device_id) REFERENCES device (device_id) ON DELETE CASCADE
+);
+CREATE INDEX device_diagnostics_device ON device_diagnostics (device_id, diagnostics_id);
+
+CREATE TABLE device_log
+(
+    log_id     INTEGER PRIMARY KEY AUTOINCREMENT,
+    device_id  INT      NOT NULL,
+    level      TEXT     NOT NULL,
+    message    TEXT     NOT NULL,
+    created_at DATETIME NOT NULL,
+
+    FOREIGN KEY (device_id) REFERENCES device (device_id) ON DELETE CASCADE
+);
+CREATE INDEX device_log_device ON device_log (device_id, log_id);
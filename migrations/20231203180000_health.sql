@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE device ADD COLUMN health TEXT NOT NULL DEFAULT 'ok' CHECK (health IN ('ok', 'warning', 'fault'));
+ALTER TABLE device ADD COLUMN health_issues TEXT NOT NULL DEFAULT '[]';
+
+ALTER TABLE room ADD COLUMN health TEXT NOT NULL DEFAULT 'ok' CHECK (health IN ('ok', 'warning', 'fault'));
@@ -0,0 +1,29 @@
+-- Add migration script here
+CREATE TABLE calibration
+(
+    calibration_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    owner_id       INT  NOT NULL,
+    room_id        INT,
+    device_id      INT,
+    metric         TEXT NOT NULL,
+    min_value      REAL,
+    max_value      REAL,
+    max_step       REAL,
+    value_offset   REAL NOT NULL DEFAULT 0,
+    value_scale    REAL NOT NULL DEFAULT 1,
+
+    CHECK ((room_id IS NULL) != (device_id IS NULL)),
+    UNIQUE (room_id, metric),
+    UNIQUE (device_id, metric),
+    FOREIGN KEY (owner_id) REFERENCES user (user_id),
+    FOREIGN KEY (room_id) REFERENCES room (room_id) ON DELETE CASCADE,
+    FOREIGN KEY (device_id) REFERENCES device (device_id) ON DELETE CASCADE
+);
+
+ALTER TABLE room_history ADD COLUMN raw_temperature REAL;
+ALTER TABLE room_history ADD COLUMN raw_humidity REAL;
+ALTER TABLE room_history ADD COLUMN raw_watthour REAL;
+UPDATE room_history SET raw_temperature = temperature, raw_humidity = humidity, raw_watthour = watthour;
+
+ALTER TABLE metric_sample ADD COLUMN raw_value REAL;
+UPDATE metric_sample SET raw_value = value;
@@ -0,0 +1,32 @@
+-- Add migration script here
+CREATE TABLE firmware
+(
+    firmware_id    INTEGER PRIMARY KEY AUTOINCREMENT,
+    owner_id       INT      NOT NULL,
+    version        TEXT     NOT NULL,
+    hardware_model TEXT     NOT NULL,
+    sha256         TEXT     NOT NULL,
+    size           INTEGER  NOT NULL,
+    path           TEXT     NOT NULL,
+    created_at     DATETIME NOT NULL DEFAULT (datetime('now')),
+
+    UNIQUE (owner_id, hardware_model, version),
+    FOREIGN KEY (owner_id) REFERENCES user (user_id)
+);
+
+CREATE TABLE firmware_update
+(
+    device_id            INTEGER PRIMARY KEY,
+    firmware_id          INT      NOT NULL,
+    previous_firmware_id INT,
+    status               TEXT     NOT NULL DEFAULT 'pending' CHECK (
+        status IN ('pending', 'sent', 'downloading', 'installing', 'success', 'failed')
+    ),
+    progress             INT      NOT NULL DEFAULT 0,
+    error                TEXT,
+    updated_at           DATETIME NOT NULL DEFAULT (datetime('now')),
+
+    FOREIGN KEY (device_id) REFERENCES device (device_id) ON DELETE CASCADE,
+    FOREIGN KEY (firmware_id) REFERENCES firmware (firmware_id) ON DELETE CASCADE,
+    FOREIGN KEY (previous_firmware_id) REFERENCES firmware (firmware_id) ON DELETE SET NULL
+);
@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE user ADD COLUMN utc_offset_minutes INT NOT NULL DEFAULT 0;
+
+ALTER TABLE device_diagnostics ADD COLUMN clock_drift_ms INTEGER;
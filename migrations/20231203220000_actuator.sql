@@ -0,0 +1,22 @@
+-- Add migration script here
+CREATE TABLE actuator
+(
+    actuator_id    INTEGER PRIMARY KEY AUTOINCREMENT,
+    owner_id       INT      NOT NULL,
+    device_id      INT      NOT NULL,
+    room_id        INT,
+    kind           TEXT     NOT NULL,
+    channel        INT      NOT NULL,
+    name           TEXT     NOT NULL DEFAULT '',
+    desired_state  REAL,
+    desired_at     DATETIME,
+    reported_state REAL,
+    reported_at    DATETIME,
+    created_at     DATETIME NOT NULL DEFAULT (datetime('now')),
+
+    UNIQUE (device_id, channel),
+    FOREIGN KEY (owner_id) REFERENCES user (user_id),
+    FOREIGN KEY (device_id) REFERENCES device (device_id) ON DELETE CASCADE,
+    FOREIGN KEY (room_id) REFERENCES room (room_id) ON DELETE SET NULL
+);
+CREATE INDEX actuator_room ON actuator (room_id);
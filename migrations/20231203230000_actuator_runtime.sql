@@ -0,0 +1,45 @@
+-- Add migration script here
+ALTER TABLE actuator ADD COLUMN on_since_ms INTEGER;
+ALTER TABLE actuator ADD COLUMN total_runtime_ms INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE actuator_transition
+(
+    transition_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    actuator_id   INT      NOT NULL,
+    room_id       INT,
+    is_on         BOOLEAN  NOT NULL,
+    created_at    DATETIME NOT NULL,
+
+    FOREIGN KEY (actuator_id) REFERENCES actuator (actuator_id) ON DELETE CASCADE
+);
+CREATE INDEX actuator_transition_actuator ON actuator_transition (actuator_id, transition_id);
+
+-- Days are local to the owner's timezone.
+CREATE TABLE actuator_runtime
+(
+    actuator_id INT     NOT NULL,
+    day         DATE    NOT NULL,
+    room_id     INT,
+    runtime_ms  INTEGER NOT NULL DEFAULT 0,
+
+    PRIMARY KEY (actuator_id, day),
+    FOREIGN KEY (actuator_id) REFERENCES actuator (actuator_id) ON DELETE CASCADE
+);
+CREATE INDEX actuator_runtime_room ON actuator_runtime (room_id, day);
+
+CREATE TABLE maintenance
+(
+    maintenance_id      INTEGER PRIMARY KEY AUTOINCREMENT,
+    owner_id            INT      NOT NULL,
+    actuator_id         INT      NOT NULL,
+    name                TEXT     NOT NULL,
+    interval_hours      REAL     NOT NULL,
+    -- Runtime of the actuator when it was last serviced.
+    serviced_runtime_ms INTEGER  NOT NULL DEFAULT 0,
+    serviced_at         DATETIME,
+    notified            BOOLEAN  NOT NULL DEFAULT FALSE,
+    created_at          DATETIME NOT NULL DEFAULT (datetime('now')),
+
+    FOREIGN KEY (owner_id) REFERENCES user (user_id),
+    FOREIGN KEY (actuator_id) REFERENCES actuator (actuator_id) ON DELETE CASCADE
+);
@@ -0,0 +1,43 @@
+-- Add migration script here
+CREATE TABLE device_event
+(
+    event_id   INTEGER PRIMARY KEY AUTOINCREMENT,
+    device_id  INT      NOT NULL,
+    room_id    INT,
+    kind       TEXT     NOT NULL,
+    value      REAL     NOT NULL,
+    created_at DATETIME NOT NULL,
+
+    FOREIGN KEY (device_id) REFERENCES device (device_id) ON DELETE CASCADE,
+    FOREIGN KEY (room_id) REFERENCES room (room_id) ON DELETE SET NULL
+);
+CREATE INDEX device_event_room ON device_event (room_id, created_at);
+CREATE INDEX device_event_device ON device_event (device_id, kind, event_id);
+
+CREATE TABLE automation
+(
+    automation_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    owner_id      INT      NOT NULL,
+    -- Events of any of the owner's devices match when both are NULL.
+    room_id       INT,
+    device_id     INT,
+    event_kind    TEXT     NOT NULL,
+    -- Any value matches when NULL.
+    event_value   REAL,
+    actuator_id   INT      NOT NULL,
+    action_value  REAL     NOT NULL,
+    created_at    DATETIME NOT NULL DEFAULT (datetime('now')),
+
+    FOREIGN KEY (owner_id) REFERENCES user (user_id),
+    FOREIGN KEY (room_id) REFERENCES room (room_id) ON DELETE CASCADE,
+    FOREIGN KEY (device_id) REFERENCES device (device_id) ON DELETE CASCADE,
+    FOREIGN KEY (actuator_id) REFERENCES actuator (actuator_id) ON DELETE CASCADE
+);
+
+ALTER TABLE actuator ADD COLUMN heating BOOLEAN NOT NULL DEFAULT FALSE;
+-- Desired state to restore once heating resumes.
+ALTER TABLE actuator ADD COLUMN paused_state REAL;
+
+ALTER TABLE room ADD COLUMN pause_heating_on_contact BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE room ADD COLUMN heating_paused_by TEXT;
+ALTER TABLE room ADD COLUMN heating_paused_until DATETIME;
@@ -0,0 +1,8 @@
+-- Add migration script here
+-- Set while a sharp temperature drop suggests a window is open.
+ALTER TABLE room ADD COLUMN window_open_at DATETIME;
+-- How long heating stays paused after an open window is detected, 0 disables
+-- the detection.
+ALTER TABLE room ADD COLUMN window_open_pause_minutes INT NOT NULL DEFAULT 15;
+
+CREATE INDEX room_history_device ON room_history (device_id, created_at);
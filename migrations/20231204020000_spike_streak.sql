@@ -0,0 +1,12 @@
+-- Add migration script here
+-- Readings in a row rejected as spikes, once enough arrive the new level is
+-- accepted.
+CREATE TABLE spike_streak
+(
+    device_id INT  NOT NULL,
+    metric    TEXT NOT NULL,
+    rejected  INT  NOT NULL DEFAULT 0,
+
+    PRIMARY KEY (device_id, metric),
+    FOREIGN KEY (device_id) REFERENCES device (device_id) ON DELETE CASCADE
+);
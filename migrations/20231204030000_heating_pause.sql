@@ -0,0 +1,19 @@
+-- Add migration script here
+-- Heating of a room stays paused while any reason for it is active.
+CREATE TABLE heating_pause
+(
+    room_id    INT      NOT NULL,
+    reason     TEXT     NOT NULL,
+    -- Paused until resumed when NULL.
+    until      DATETIME,
+    created_at DATETIME NOT NULL DEFAULT (datetime('now')),
+
+    PRIMARY KEY (room_id, reason),
+    FOREIGN KEY (room_id) REFERENCES room (room_id) ON DELETE CASCADE
+);
+
+INSERT INTO heating_pause(room_id, reason, until)
+    SELECT room_id, heating_paused_by, heating_paused_until FROM room WHERE heating_paused_by IS NOT NULL;
+
+ALTER TABLE room DROP COLUMN heating_paused_by;
+ALTER TABLE room DROP COLUMN heating_paused_until;
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- MQTT messages carry no device credentials, so the bridge only accepts them
+-- for devices their owner has enabled it for.
+ALTER TABLE device ADD COLUMN mqtt_enabled BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Time of the device's last message over any transport, in milliseconds since
+-- the epoch. Reported actuator states are only trusted while it is recent.
+ALTER TABLE device ADD COLUMN last_seen_ms INTEGER;
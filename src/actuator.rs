@@ -0,0 +1,541 @@
+use crate::esp_websockets::WsOutputData;
+use crate::events::{self, DeviceEvent};
+use crate::hub::{DeviceHub, HubError};
+use crate::runtime;
+use crate::utils::jwt::JWTAuth;
+use axum::extract::{Path, Query};
+use axum::http::{HeaderValue, StatusCode};
+use axum::routing::{delete, get, patch, post, put};
+use axum::{Extension, Json, Router};
+use http::header::{CONTENT_TYPE, COOKIE};
+use http::Method;
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+use std::str::FromStr;
+use tower_http::cors::CorsLayer;
+
+/// What an output of a device drives. Relays are either 0 or 1, dimmers and
+/// valves are set in percent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActuatorKind {
+    Relay,
+    Dimmer,
+    Valve,
+}
+
+impl ActuatorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActuatorKind::Relay => "relay",
+            ActuatorKind::Dimmer => "dimmer",
+            ActuatorKind::Valve => "valve",
+        }
+    }
+
+    pub fn is_valid(&self, value: f64) -> bool {
+        match self {
+            ActuatorKind::Relay => value == 0.0 || value == 1.0,
+            ActuatorKind::Dimmer | ActuatorKind::Valve => (0.0..=100.0).contains(&value),
+        }
+    }
+}
+
+impl FromStr for ActuatorKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "relay" => Ok(ActuatorKind::Relay),
+            "dimmer" => Ok(ActuatorKind::Dimmer),
+            "valve" => Ok(ActuatorKind::Valve),
+            _ => Err(anyhow::Error::msg("Unknown actuator type")),
+        }
+    }
+}
+
+/// State of one output, sent by the device whenever it changes and after
+/// connecting.
+#[derive(Debug, Deserialize)]
+pub struct StateReport {
+    pub channel: u32,
+    pub value: f64,
+    /// Registers the channel as an actuator if it is not known yet.
+    #[serde(rename = "type")]
+    pub kind: Option<ActuatorKind>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Actuator {
+    pub id: i64,
+    #[serde(rename = "type")]
+    pub kind: ActuatorKind,
+    pub device_id: i64,
+    pub room_id: Option<i64>,
+    pub channel: u32,
+    pub name: String,
+    /// Switched off while heating of the room is paused.
+    pub heating: bool,
+    /// Last state reported by the device.
+    pub state: Option<f64>,
+    pub desired_state: Option<f64>,
+    /// The device has not confirmed the desired state yet.
+    pub pending: bool,
+    pub reported_at: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ActuatorFilter {
+    pub room_id: Option<i64>,
+    pub device_id: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CreateActuatorDto {
+    device_id: i64,
+    channel: u32,
+    #[serde(rename = "type")]
+    kind: ActuatorKind,
+    name: Option<String>,
+    /// Defaults to the room of the device.
+    room_id: Option<i64>,
+    #[serde(default)]
+    heating: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct UpdateActuatorDto {
+    id: i64,
+    name: Option<String>,
+    room_id: Option<i64>,
+    heating: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SetStateDto {
+    value: f64,
+}
+
+pub fn router(pool: SqlitePool) -> Router {
+    Router::new()
+        .route("/", get(get_actuators_controller))
+        .route("/", post(create_actuator_controller))
+        .route("/", patch(update_actuator_controller))
+        .route("/:id", delete(delete_actuator_controller))
+        .route("/:id/state", put(set_state_controller))
+        .layer(
+            CorsLayer::new()
+                .allow_origin("http://localhost:5173".parse::<HeaderValue>().unwrap())
+                .allow_credentials(true)
+                .allow_headers([COOKIE, CONTENT_TYPE])
+                .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::PUT, Method::DELETE]),
+        )
+        .layer(Extension(pool))
+}
+
+/// Actuators of `user_id`. Those without a room of their own belong to the
+/// room of their device.
+pub async fn list_actuators(
+    pool: &SqlitePool,
+    user_id: u32,
+    filter: ActuatorFilter,
+) -> anyhow::Result<Vec<Actuator>> {
+    fetch_actuators(pool, user_id, filter, None).await
+}
+
+async fn get_actuator(pool: &SqlitePool, user_id: u32, actuator_id: i64) -> anyhow::Result<Actuator> {
+    fetch_actuators(pool, user_id, ActuatorFilter::default(), Some(actuator_id))
+        .await?
+        .pop()
+        .ok_or(anyhow::Error::msg("Actuator not found"))
+}
+
+async fn fetch_actuators(
+    pool: &SqlitePool,
+    user_id: u32,
+    filter: ActuatorFilter,
+    actuator_id: Option<i64>,
+) -> anyhow::Result<Vec<Actuator>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT a.actuator_id AS "actuator_id!", a.kind, a.device_id, COALESCE(a.room_id, d.room_id) AS "room_id: i64",
+               a.channel, a.name, a.heating, a.desired_state, a.reported_state, a.reported_at
+            FROM actuator a
+            INNER JOIN device d ON d.device_id = a.device_id
+            WHERE a.owner_id = ?
+            AND (? IS NULL OR COALESCE(a.room_id, d.room_id) = ?)
+            AND (? IS NULL OR a.device_id = ?)
+            AND (? IS NULL OR a.actuator_id = ?)
+            ORDER BY a.device_id, a.channel
+        "#,
+        user_id,
+        filter.room_id,
+        filter.room_id,
+        filter.device_id,
+        filter.device_id,
+        actuator_id,
+        actuator_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut res = vec![];
+
+    for row in rows {
+        let pending = match (row.desired_state, row.reported_state) {
+            (Some(desired), Some(reported)) => desired != reported,
+            (Some(_), None) => true,
+            _ => false,
+        };
+        res.push(Actuator {
+            id: row.actuator_id,
+            kind: row.kind.parse()?,
+            device_id: row.device_id,
+            room_id: row.room_id,
+            channel: row.channel as u32,
+            name: row.name,
+            heating: row.heating,
+            state: row.reported_state,
+            desired_state: row.desired_state,
+            pending,
+            reported_at: row.reported_at.map(|d| d.to_string()),
+        });
+    }
+
+    Ok(res)
+}
+
+/// Stores the state reported by the device. While no command is pending the
+/// desired state follows the device, so switching it by hand is not undone
+/// on the next reconnect. Returns the actuator if its runtime was booked.
+pub async fn record_state(
+    conn: &mut SqliteConnection,
+    device_id: i64,
+    report: StateReport,
+) -> anyhow::Result<Option<i64>> {
+    if let Some(kind) = report.kind {
+        let kind = kind.as_str();
+        sqlx::query!(
+            r#"
+            INSERT INTO actuator(owner_id, device_id, kind, channel)
+                SELECT owner_id, device_id, ?, ? FROM device WHERE device_id = ?
+                ON CONFLICT(device_id, channel) DO NOTHING
+            "#,
+            kind,
+            report.channel,
+            device_id,
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    sqlx::query!(
+        r#"
+        UPDATE actuator SET
+            desired_state = CASE WHEN desired_state IS NULL OR desired_state = reported_state
+                THEN ? ELSE desired_state END,
+            desired_at = CASE WHEN desired_state IS NULL OR desired_state = reported_state
+                THEN datetime('now') ELSE desired_at END,
+            reported_state = ?,
+            reported_at = datetime('now')
+            WHERE device_id = ? AND channel = ?
+        "#,
+        report.value,
+        report.value,
+        device_id,
+        report.channel,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    runtime::record_state(conn, device_id, report.channel, report.value).await
+}
+
+pub async fn emit_state(pool: &SqlitePool, device_id: i64, channel: u32) -> anyhow::Result<()> {
+    let row = sqlx::query!(
+        r#"
+        SELECT a.actuator_id AS "actuator_id!", COALESCE(a.room_id, d.room_id) AS "room_id: i64",
+               a.reported_state, a.desired_state
+            FROM actuator a
+            INNER JOIN device d ON d.device_id = a.device_id
+            WHERE a.device_id = ? AND a.channel = ?
+        "#,
+        device_id,
+        channel,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(row) = row {
+        events::emit(DeviceEvent::ActuatorState {
+            actuator_id: row.actuator_id,
+            device_id,
+            room_id: row.room_id,
+            state: row.reported_state,
+            desired_state: row.desired_state,
+        });
+    }
+
+    Ok(())
+}
+
+/// Commands for every output of the device that has not reached its desired
+/// state, sent again whenever the device connects.
+pub async fn pending_commands(pool: &SqlitePool, device_id: i64) -> anyhow::Result<Vec<WsOutputData>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT channel, desired_state AS "desired_state!"
+            FROM actuator
+            WHERE device_id = ? AND desired_state IS NOT NULL
+            AND (reported_state IS NULL OR reported_state != desired_state)
+        "#,
+        device_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| WsOutputData::SetState {
+            channel: row.channel as u32,
+            value: row.desired_state,
+        })
+        .collect())
+}
+
+async fn get_actuators_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Query(filter): Query<ActuatorFilter>,
+) -> Result<Json<Vec<Actuator>>, (StatusCode, String)> {
+    let res = list_actuators(&pool, jwt_auth.id, filter)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn create_actuator_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Json(create_actuator_dto): Json<CreateActuatorDto>,
+) -> Result<Json<Actuator>, (StatusCode, String)> {
+    let res = create_actuator_service(pool, jwt_auth.id, create_actuator_dto)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn create_actuator_service(
+    pool: SqlitePool,
+    user_id: u32,
+    dto: CreateActuatorDto,
+) -> anyhow::Result<Actuator> {
+    check_ownership(&pool, user_id, Some(dto.device_id), dto.room_id).await?;
+
+    let kind = dto.kind.as_str();
+    let name = dto.name.unwrap_or_default();
+    let id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO actuator(owner_id, device_id, room_id, kind, channel, name, heating)
+        VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING actuator_id
+        "#,
+        user_id,
+        dto.device_id,
+        dto.room_id,
+        kind,
+        dto.channel,
+        name,
+        dto.heating,
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    get_actuator(&pool, user_id, id).await
+}
+
+async fn update_actuator_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Json(update_actuator_dto): Json<UpdateActuatorDto>,
+) -> Result<Json<Actuator>, (StatusCode, String)> {
+    let res = update_actuator_service(pool, jwt_auth.id, update_actuator_dto)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn update_actuator_service(
+    pool: SqlitePool,
+    user_id: u32,
+    dto: UpdateActuatorDto,
+) -> anyhow::Result<Actuator> {
+    check_ownership(&pool, user_id, None, dto.room_id).await?;
+
+    let res = sqlx::query!(
+        r#"
+        UPDATE actuator
+            SET name = COALESCE(?, name),
+            room_id = COALESCE(?, room_id),
+            heating = COALESCE(?, heating)
+            WHERE owner_id = ? AND actuator_id = ?
+        "#,
+        dto.name,
+        dto.room_id,
+        dto.heating,
+        user_id,
+        dto.id,
+    )
+    .execute(&pool)
+    .await?;
+    if res.rows_affected() == 0 {
+        return Err(anyhow::Error::msg("Actuator not found"));
+    }
+
+    get_actuator(&pool, user_id, dto.id).await
+}
+
+async fn delete_actuator_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Path(id): Path<i64>,
+) -> Result<String, (StatusCode, String)> {
+    sqlx::query!(
+        "DELETE FROM actuator WHERE owner_id = ? AND actuator_id = ?",
+        jwt_auth.id,
+        id,
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| {
+        let err = e.to_string();
+        (StatusCode::INTERNAL_SERVER_ERROR, err)
+    })?;
+
+    Ok("Successfully deleted".to_string())
+}
+
+async fn set_state_controller(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(hub): Extension<DeviceHub>,
+    jwt_auth: JWTAuth,
+    Path(id): Path<i64>,
+    Json(set_state_dto): Json<SetStateDto>,
+) -> Result<Json<Actuator>, (StatusCode, String)> {
+    let actuator = get_actuator(&pool, jwt_auth.id, id)
+        .await
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))?;
+    if !actuator.kind.is_valid(set_state_dto.value) {
+        return Err((StatusCode::BAD_REQUEST, "Invalid state for this actuator".to_string()));
+    }
+
+    let res = set_state_service(pool, hub, jwt_auth.id, actuator, set_state_dto.value)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn set_state_service(
+    pool: SqlitePool,
+    hub: DeviceHub,
+    user_id: u32,
+    actuator: Actuator,
+    value: f64,
+) -> anyhow::Result<Actuator> {
+    command(&pool, &hub, actuator.id, value).await?;
+    get_actuator(&pool, user_id, actuator.id).await
+}
+
+/// Stores the desired state and sends it to the device. Offline devices get
+/// it when they reconnect. Heating actuators of a room whose heating is
+/// paused stay off, the value is applied once heating resumes.
+pub async fn command(pool: &SqlitePool, hub: &DeviceHub, actuator_id: i64, value: f64) -> anyhow::Result<()> {
+    if value > 0.0 {
+        let deferred = sqlx::query!(
+            r#"
+            UPDATE actuator SET paused_state = ?
+                WHERE actuator_id = ? AND heating AND EXISTS (
+                    SELECT 1 FROM heating_pause p
+                        INNER JOIN device d ON d.device_id = actuator.device_id
+                        WHERE p.room_id = COALESCE(actuator.room_id, d.room_id)
+                )
+            "#,
+            value,
+            actuator_id,
+        )
+        .execute(pool)
+        .await?;
+        if deferred.rows_affected() > 0 {
+            return Ok(());
+        }
+    }
+
+    let actuator = sqlx::query!(
+        r#"
+        UPDATE actuator SET desired_state = ?, desired_at = datetime('now')
+            WHERE actuator_id = ?
+            RETURNING device_id, channel
+        "#,
+        value,
+        actuator_id,
+    )
+    .fetch_one(pool)
+    .await?;
+    emit_state(pool, actuator.device_id, actuator.channel as u32).await?;
+
+    let msg = WsOutputData::SetState {
+        channel: actuator.channel as u32,
+        value,
+    };
+    match hub.send(actuator.device_id, msg).await {
+        Ok(()) | Err(HubError::NotConnected) => {}
+        Err(e) => println!("Failed to send state to device {}: {}", actuator.device_id, e),
+    }
+
+    Ok(())
+}
+
+pub async fn check_ownership(
+    pool: &SqlitePool,
+    user_id: u32,
+    device_id: Option<i64>,
+    room_id: Option<i64>,
+) -> anyhow::Result<()> {
+    if let Some(device_id) = device_id {
+        sqlx::query!(
+            "SELECT device_id FROM device WHERE device_id = ? AND owner_id = ?",
+            device_id,
+            user_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(anyhow::Error::msg("Device not found"))?;
+    }
+    if let Some(room_id) = room_id {
+        sqlx::query!(
+            "SELECT room_id FROM room WHERE room_id = ? AND owner_id = ?",
+            room_id,
+            user_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or(anyhow::Error::msg("Room not found"))?;
+    }
+
+    Ok(())
+}
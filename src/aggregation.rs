@@ -0,0 +1,175 @@
+use crate::utils::config::env_or;
+use serde::{Deserialize, Serialize};
+use sqlx::SqliteConnection;
+use std::str::FromStr;
+
+/// How the readings of several sensors in one room are combined into the
+/// room's current values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    #[default]
+    Mean,
+    Median,
+    Min,
+    Max,
+    /// Only the room's primary device counts, falling back to the mean while it
+    /// has not reported.
+    Primary,
+}
+
+impl Aggregation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Aggregation::Mean => "mean",
+            Aggregation::Median => "median",
+            Aggregation::Min => "min",
+            Aggregation::Max => "max",
+            Aggregation::Primary => "primary",
+        }
+    }
+
+    /// Combines `values`. `primary` is the primary sensor's value, if any.
+    pub fn apply(&self, values: &[f64], primary: Option<f64>) -> Option<f64> {
+        if values.is_empty() {
+            return None;
+        }
+
+        let res = match self {
+            Aggregation::Mean => values.iter().sum::<f64>() / values.len() as f64,
+            Aggregation::Median => {
+                let mut sorted = values.to_vec();
+                sorted.sort_by(f64::total_cmp);
+                let mid = sorted.len() / 2;
+                if sorted.len() % 2 == 1 {
+                    sorted[mid]
+                } else {
+                    (sorted[mid - 1] + sorted[mid]) / 2.0
+                }
+            }
+            Aggregation::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+            Aggregation::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            Aggregation::Primary => match primary {
+                Some(primary) => primary,
+                None => return Aggregation::Mean.apply(values, None),
+            },
+        };
+
+        Some(res)
+    }
+}
+
+impl FromStr for Aggregation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mean" => Ok(Aggregation::Mean),
+            "median" => Ok(Aggregation::Median),
+            "min" => Ok(Aggregation::Min),
+            "max" => Ok(Aggregation::Max),
+            "primary" => Ok(Aggregation::Primary),
+            _ => Err(anyhow::Error::msg("Unknown aggregation")),
+        }
+    }
+}
+
+/// Recomputes the current values of `room_id` from the latest reading of
+/// every sensor in it. Sensors that have not reported within
+/// `SENSOR_STALE_MS` are left out. Watt-hours are summed, since every meter
+/// measures its own circuit.
+pub async fn refresh_room_current(conn: &mut SqliteConnection, room_id: i64) -> anyhow::Result<()> {
+    let room = sqlx::query!(
+        "SELECT room_id, aggregation, primary_device_id FROM room WHERE room_id = ?",
+        room_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    let room = match room {
+        Some(room) => room,
+        None => return Ok(()),
+    };
+
+    let stale_after = format!("-{} seconds", env_or("SENSOR_STALE_MS", 3600000u64) / 1000);
+    let sensors = sqlx::query!(
+        r#"
+        SELECT device_id, last_temperature as "temperature!", last_humidity as "humidity!",
+            last_watthour as "watthour!", last_reading_at as "reading_at!: String"
+            FROM device
+            WHERE room_id = ? AND last_reading_at IS NOT NULL AND last_reading_at >= datetime('now', ?)
+            AND last_temperature IS NOT NULL AND last_humidity IS NOT NULL AND last_watthour IS NOT NULL
+        "#,
+        room.room_id,
+        stale_after,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    if sensors.is_empty() {
+        return Ok(());
+    }
+
+    let aggregation = room.aggregation.parse::<Aggregation>().unwrap_or_default();
+    let primary = sensors
+        .iter()
+        .find(|s| Some(s.device_id) == room.primary_device_id);
+    let temperatures: Vec<f64> = sensors.iter().map(|s| s.temperature).collect();
+    let humidities: Vec<f64> = sensors.iter().map(|s| s.humidity).collect();
+
+    let temperature = aggregation.apply(&temperatures, primary.map(|p| p.temperature));
+    let humidity = aggregation.apply(&humidities, primary.map(|p| p.humidity));
+    let watthour: f64 = sensors.iter().map(|s| s.watthour).sum();
+    let updated_at = sensors.iter().map(|s| s.reading_at.as_str()).max();
+
+    sqlx::query!(
+        r#"
+        UPDATE room
+            SET current_temperature = ?, current_humidity = ?, current_watthour = ?,
+            current_updated_at = ?
+            WHERE room_id = ?
+        "#,
+        temperature,
+        humidity,
+        watthour,
+        updated_at,
+        room.room_id,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALUES: [f64; 4] = [21.0, 19.0, 24.0, 20.0];
+
+    #[test]
+    fn combines_values() {
+        assert_eq!(Aggregation::Mean.apply(&VALUES, None), Some(21.0));
+        assert_eq!(Aggregation::Median.apply(&VALUES, None), Some(20.5));
+        assert_eq!(Aggregation::Median.apply(&VALUES[..3], None), Some(21.0));
+        assert_eq!(Aggregation::Min.apply(&VALUES, None), Some(19.0));
+        assert_eq!(Aggregation::Max.apply(&VALUES, None), Some(24.0));
+    }
+
+    #[test]
+    fn primary_falls_back_to_mean() {
+        assert_eq!(Aggregation::Primary.apply(&VALUES, Some(19.0)), Some(19.0));
+        assert_eq!(Aggregation::Primary.apply(&VALUES, None), Some(21.0));
+    }
+
+    #[test]
+    fn nothing_to_combine() {
+        for aggregation in [
+            Aggregation::Mean,
+            Aggregation::Median,
+            Aggregation::Min,
+            Aggregation::Max,
+            Aggregation::Primary,
+        ] {
+            assert_eq!(aggregation.apply(&[], Some(20.0)), None);
+        }
+    }
+}
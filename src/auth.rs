@@ -1,12 +1,13 @@
+use crate::clock;
 use crate::utils::jwt::JWTAuth;
 use argon2::password_hash::rand_core::OsRng;
 use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use axum::http::StatusCode;
-use axum::routing::post;
+use axum::routing::{post, put};
 use axum::{Extension, Json, Router};
 use serde::{Deserialize, Serialize};
-use sqlx::{query, Executor, SqlitePool};
+use sqlx::SqlitePool;
 use tower_cookies::{Cookie, CookieManagerLayer, Cookies};
 use tower_http::cors::CorsLayer;
 
@@ -14,6 +15,7 @@ pub fn router(conn: SqlitePool) -> Router {
     Router::new()
         .route("/register", post(register_controller))
         .route("/login", post(login_controller))
+        .route("/timezone", put(timezone_controller))
         .layer(CookieManagerLayer::new())
         .layer(Extension(conn))
         .layer(CorsLayer::permissive())
@@ -78,6 +80,28 @@ async fn login_service(conn: &SqlitePool, login_dto: LoginDto) -> anyhow::Result
     Ok(token_str)
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+struct TimezoneDto {
+    utc_offset_minutes: i32,
+}
+
+/// Sets the offset of the home's timezone, passed on to devices with the time.
+async fn timezone_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Json(timezone_dto): Json<TimezoneDto>,
+) -> Result<Json<TimezoneDto>, (StatusCode, String)> {
+    if !clock::is_valid_offset(timezone_dto.utc_offset_minutes) {
+        return Err((StatusCode::BAD_REQUEST, "Invalid UTC offset".to_string()));
+    }
+
+    clock::set_utc_offset(&pool, jwt_auth.id, timezone_dto.utc_offset_minutes)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(timezone_dto))
+}
+
 async fn register_controller(
     Extension(pool): Extension<SqlitePool>,
     cookies: Cookies,
@@ -0,0 +1,205 @@
+use crate::actuator;
+use crate::hub::DeviceHub;
+use crate::utils::jwt::JWTAuth;
+use axum::extract::Path;
+use axum::http::{HeaderValue, StatusCode};
+use axum::routing::{delete, get, post};
+use axum::{Extension, Json, Router};
+use http::header::{CONTENT_TYPE, COOKIE};
+use http::Method;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tower_http::cors::CorsLayer;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CreateAutomationDto {
+    /// Limits the trigger to events of one room or device.
+    room_id: Option<i64>,
+    device_id: Option<i64>,
+    event_kind: String,
+    /// Any value matches when missing.
+    event_value: Option<f64>,
+    actuator_id: i64,
+    action_value: f64,
+}
+
+/// Sets an actuator whenever a matching device event arrives, e.g. toggling
+/// the light on a button press.
+#[derive(Serialize, Deserialize, Clone)]
+struct Automation {
+    id: i64,
+    room_id: Option<i64>,
+    device_id: Option<i64>,
+    event_kind: String,
+    event_value: Option<f64>,
+    actuator_id: i64,
+    action_value: f64,
+}
+
+pub fn router(pool: SqlitePool) -> Router {
+    Router::new()
+        .route("/", get(get_automations_controller))
+        .route("/", post(create_automation_controller))
+        .route("/:id", delete(delete_automation_controller))
+        .layer(
+            CorsLayer::new()
+                .allow_origin("http://localhost:5173".parse::<HeaderValue>().unwrap())
+                .allow_credentials(true)
+                .allow_headers([COOKIE, CONTENT_TYPE])
+                .allow_methods([Method::GET, Method::POST, Method::DELETE]),
+        )
+        .layer(Extension(pool))
+}
+
+/// Runs the automations of the device's owner triggered by the event.
+pub async fn trigger(
+    pool: &SqlitePool,
+    hub: &DeviceHub,
+    device_id: i64,
+    room_id: Option<i64>,
+    kind: &str,
+    value: f64,
+) -> anyhow::Result<()> {
+    let actions = sqlx::query!(
+        r#"
+        SELECT a.actuator_id, a.action_value
+            FROM automation a
+            INNER JOIN device d ON d.owner_id = a.owner_id
+            WHERE d.device_id = ? AND a.event_kind = ?
+            AND (a.event_value IS NULL OR a.event_value = ?)
+            AND (a.device_id IS NULL OR a.device_id = d.device_id)
+            AND (a.room_id IS NULL OR a.room_id = ?)
+        "#,
+        device_id,
+        kind,
+        value,
+        room_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    // One unreachable actuator must not keep the others from switching.
+    for action in actions {
+        if let Err(e) = actuator::command(pool, hub, action.actuator_id, action.action_value).await {
+            println!("Automation failed to set actuator {}: {}", action.actuator_id, e);
+        }
+    }
+
+    Ok(())
+}
+
+async fn get_automations_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+) -> Result<Json<Vec<Automation>>, (StatusCode, String)> {
+    let res = get_automations_service(pool, jwt_auth.id)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn get_automations_service(pool: SqlitePool, user_id: u32) -> anyhow::Result<Vec<Automation>> {
+    let rows = sqlx::query!("SELECT * FROM automation WHERE owner_id = ?", user_id)
+        .fetch_all(&pool)
+        .await?;
+
+    let mut res = vec![];
+
+    for row in rows {
+        res.push(Automation {
+            id: row.automation_id,
+            room_id: row.room_id,
+            device_id: row.device_id,
+            event_kind: row.event_kind,
+            event_value: row.event_value,
+            actuator_id: row.actuator_id,
+            action_value: row.action_value,
+        });
+    }
+
+    Ok(res)
+}
+
+async fn create_automation_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Json(create_automation_dto): Json<CreateAutomationDto>,
+) -> Result<Json<Automation>, (StatusCode, String)> {
+    let res = create_automation_service(pool, jwt_auth.id, create_automation_dto)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn create_automation_service(
+    pool: SqlitePool,
+    user_id: u32,
+    dto: CreateAutomationDto,
+) -> anyhow::Result<Automation> {
+    let kind = sqlx::query_scalar!(
+        "SELECT kind FROM actuator WHERE actuator_id = ? AND owner_id = ?",
+        dto.actuator_id,
+        user_id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or(anyhow::Error::msg("Actuator not found"))?;
+    if !kind.parse::<actuator::ActuatorKind>()?.is_valid(dto.action_value) {
+        return Err(anyhow::Error::msg("Invalid state for this actuator"));
+    }
+    actuator::check_ownership(&pool, user_id, dto.device_id, dto.room_id).await?;
+
+    let res = sqlx::query!(
+        r#"
+        INSERT INTO automation(owner_id, room_id, device_id, event_kind, event_value, actuator_id, action_value)
+        VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING *
+        "#,
+        user_id,
+        dto.room_id,
+        dto.device_id,
+        dto.event_kind,
+        dto.event_value,
+        dto.actuator_id,
+        dto.action_value,
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    Ok(Automation {
+        id: res.automation_id,
+        room_id: res.room_id,
+        device_id: res.device_id,
+        event_kind: res.event_kind,
+        event_value: res.event_value,
+        actuator_id: res.actuator_id,
+        action_value: res.action_value,
+    })
+}
+
+async fn delete_automation_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Path(id): Path<i64>,
+) -> Result<String, (StatusCode, String)> {
+    sqlx::query!(
+        "DELETE FROM automation WHERE owner_id = ? AND automation_id = ?",
+        jwt_auth.id,
+        id,
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| {
+        let err = e.to_string();
+        (StatusCode::INTERNAL_SERVER_ERROR, err)
+    })?;
+
+    Ok("Successfully deleted".to_string())
+}
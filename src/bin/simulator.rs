@@ -0,0 +1,416 @@
+use clap::Parser;
+use futures_util::{SinkExt, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::f64::consts::TAU;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_tungstenite::tungstenite::Message;
+
+const FIRMWARE_VERSION: &str = "sim-1.0.0";
+const HARDWARE_MODEL: &str = "simulator";
+/// Always-on consumption of the device and whatever else is on its meter.
+const BASE_LOAD_W: f64 = 40.0;
+/// Relay output switching the room's heater.
+const HEATER_CHANNEL: u64 = 0;
+
+/// Simulates ESP devices connected to `/ws/:device_id`, for developing
+/// without hardware or as a load generator.
+///
+/// Unclaimed devices print their claim code. Credentials handed out on
+/// claiming are kept in the token file, so devices stay claimed across runs.
+///
+/// Run with `cargo run --features simulator --bin simulator -- --count 10`.
+#[derive(Parser, Debug)]
+#[command(name = "simulator")]
+struct Args {
+    /// Base WebSocket URL of the backend.
+    #[arg(long, default_value = "ws://localhost:3000")]
+    url: String,
+    /// Number of simulated devices.
+    #[arg(short, long, default_value_t = 1)]
+    count: u32,
+    /// Id of the first device, the others follow consecutively.
+    #[arg(long, default_value_t = 1000)]
+    first_id: i64,
+    /// Readings sent per second by every device.
+    #[arg(short, long, default_value_t = 0.2)]
+    rate: f64,
+    /// Seed of the random generators, runs with the same seed are identical.
+    #[arg(short, long, default_value_t = 0)]
+    seed: u64,
+    /// How many times faster than real time the simulated day passes.
+    #[arg(long, default_value_t = 1.0)]
+    time_scale: f64,
+    /// File keeping the credentials of claimed devices.
+    #[arg(long, default_value = "simulator-tokens.json")]
+    tokens: PathBuf,
+}
+
+#[derive(Default)]
+struct Stats {
+    connected: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+struct Tokens {
+    path: PathBuf,
+    tokens: Mutex<HashMap<i64, String>>,
+}
+
+impl Tokens {
+    fn load(path: PathBuf) -> Self {
+        let tokens = std::fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+
+        Tokens {
+            path,
+            tokens: Mutex::new(tokens),
+        }
+    }
+
+    fn get(&self, device_id: i64) -> Option<String> {
+        self.tokens.lock().unwrap().get(&device_id).cloned()
+    }
+
+    fn set(&self, device_id: i64, token: String) {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.insert(device_id, token);
+        if let Err(e) = std::fs::write(&self.path, serde_json::to_vec_pretty(&*tokens).unwrap()) {
+            println!("Failed to save tokens to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Lumped thermal model of the room a device sits in.
+struct Room {
+    temperature: f64,
+    humidity: f64,
+    /// Cumulative energy meter reading.
+    watthour: f64,
+    heating: bool,
+    /// Degrees per hour lost for every degree of difference to the outside.
+    loss: f64,
+    /// Degrees per hour the heater adds at full power.
+    heater_gain: f64,
+    heater_power_w: f64,
+}
+
+impl Room {
+    fn new(rng: &mut StdRng) -> Self {
+        Room {
+            temperature: rng.gen_range(17.0..23.0),
+            humidity: rng.gen_range(40.0..60.0),
+            watthour: rng.gen_range(0.0..10000.0),
+            heating: false,
+            loss: rng.gen_range(0.05..0.2),
+            heater_gain: rng.gen_range(1.5..4.0),
+            heater_power_w: rng.gen_range(800.0..2000.0),
+        }
+    }
+
+    fn step(&mut self, hours: f64, outside: f64, rng: &mut StdRng) {
+        let gain = if self.heating { self.heater_gain } else { 0.0 };
+        self.temperature += (gain - self.loss * (self.temperature - outside)) * hours;
+        self.temperature += rng.gen_range(-0.05..0.05);
+
+        // Warm air holds more water, so relative humidity drops as the room heats.
+        let target = 50.0 - 1.5 * (self.temperature - 20.0);
+        self.humidity += (target - self.humidity) * (hours * 2.0).min(1.0) + rng.gen_range(-0.5..0.5);
+        self.humidity = self.humidity.clamp(15.0, 95.0);
+
+        let power = BASE_LOAD_W + if self.heating { self.heater_power_w } else { 0.0 };
+        self.watthour += power * hours;
+    }
+}
+
+/// Wall clock of the simulation, sped up by `--time-scale`.
+struct Clock {
+    started: Instant,
+    epoch: f64,
+    scale: f64,
+}
+
+impl Clock {
+    fn new(scale: f64) -> Self {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+
+        Clock {
+            started: Instant::now(),
+            epoch,
+            scale,
+        }
+    }
+
+    /// Hour of the simulated day, 0 to 24.
+    fn hour(&self) -> f64 {
+        let secs = self.epoch + self.started.elapsed().as_secs_f64() * self.scale;
+        (secs % 86400.0) / 3600.0
+    }
+}
+
+/// Coldest before sunrise, warmest mid-afternoon.
+fn outside_temperature(hour: f64) -> f64 {
+    8.0 + 6.0 * ((hour - 9.0) / 24.0 * TAU).sin()
+}
+
+/// Expected number of movements per hour, people sleep at night.
+fn motion_rate(hour: f64) -> f64 {
+    if (7.0..23.0).contains(&hour) {
+        6.0
+    } else {
+        0.3
+    }
+}
+
+struct Device {
+    id: i64,
+    args: Arc<Args>,
+    tokens: Arc<Tokens>,
+    stats: Arc<Stats>,
+    clock: Arc<Clock>,
+    rng: StdRng,
+    room: Room,
+    firmware_version: String,
+}
+
+impl Device {
+    async fn run(mut self) {
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            match self.session().await {
+                Ok(()) => backoff = Duration::from_secs(1),
+                Err(e) => {
+                    println!("Device {}: {}, retrying in {:?}", self.id, e, backoff);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(Duration::from_secs(60));
+                }
+            }
+        }
+    }
+
+    /// Runs one connection. Returns `Ok` when the device should reconnect
+    /// right away, e.g. after being claimed or updated.
+    async fn session(&mut self) -> anyhow::Result<()> {
+        let mut url = format!("{}/ws/{}", self.args.url.trim_end_matches('/'), self.id);
+        if let Some(token) = self.tokens.get(self.id) {
+            url = format!("{}?token={}", url, token);
+        }
+        let (mut socket, _) = tokio_tungstenite::connect_async(url).await?;
+
+        // A new boot id makes the server restart its sequence tracking.
+        let boot_id = format!("{:016x}", self.rng.gen::<u64>());
+        let hello = json!({
+            "device_id": self.id,
+            "type": "hello",
+            "data": {
+                "protocol_version": 1,
+                "firmware_version": self.firmware_version,
+                "hardware_model": HARDWARE_MODEL,
+                "capabilities": ["ota", "relay"],
+                "boot_id": boot_id,
+            },
+        });
+        socket.send(Message::Text(hello.to_string())).await?;
+
+        self.stats.connected.fetch_add(1, Ordering::Relaxed);
+        let res = self.exchange(&mut socket).await;
+        self.stats.connected.fetch_sub(1, Ordering::Relaxed);
+        _ = socket.close(None).await;
+
+        res
+    }
+
+    async fn exchange<S>(&mut self, socket: &mut S) -> anyhow::Result<()>
+    where
+        S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>>
+            + SinkExt<Message, Error = tokio_tungstenite::tungstenite::Error>
+            + Unpin,
+    {
+        let interval = Duration::from_secs_f64(1.0 / self.args.rate.max(0.001));
+        let mut ticker = tokio::time::interval(interval);
+        let mut seq: i64 = 0;
+        let mut presence_timeout = Duration::from_secs(60);
+        let mut last_move: Option<Instant> = None;
+
+        loop {
+            tokio::select! {
+                msg = socket.next() => {
+                    let msg = match msg {
+                        Some(Ok(Message::Text(text))) => text,
+                        Some(Ok(Message::Close(_))) | None => {
+                            return Err(anyhow::Error::msg("connection closed"));
+                        }
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => return Err(e.into()),
+                    };
+                    self.stats.received.fetch_add(1, Ordering::Relaxed);
+
+                    let msg: Value = serde_json::from_str(&msg)?;
+                    let data = &msg["data"];
+                    match msg["type"].as_str().unwrap_or_default() {
+                        "settings" => {
+                            if let Some(ms) = data["presence_timeout"].as_u64() {
+                                presence_timeout = Duration::from_millis(ms);
+                            }
+                        }
+                        "claim_code" => {
+                            println!(
+                                "Device {} is unclaimed, claim code: {}",
+                                self.id,
+                                data["code"].as_str().unwrap_or_default()
+                            );
+                        }
+                        "credentials" => {
+                            if let Some(token) = data["token"].as_str() {
+                                println!("Device {} has been claimed", self.id);
+                                self.tokens.set(self.id, token.to_string());
+                                return Ok(());
+                            }
+                        }
+                        "rejected" => {
+                            let reason = data["reason"].as_str().unwrap_or_default();
+                            return Err(anyhow::Error::msg(format!("rejected: {}", reason)));
+                        }
+                        // Report the heater so the server knows about it and
+                        // resends a command missed while disconnected.
+                        "welcome" => {
+                            self.report_heater(socket, &mut seq).await?;
+                        }
+                        "set_state" if data["channel"].as_u64() == Some(HEATER_CHANNEL) => {
+                            self.room.heating = data["value"].as_f64().unwrap_or_default() > 0.0;
+                            self.report_heater(socket, &mut seq).await?;
+                        }
+                        "update" => {
+                            let version = data["version"].as_str().unwrap_or_default().to_string();
+                            self.update(socket, &mut seq, version).await?;
+                            return Ok(());
+                        }
+                        // Echoes of our own messages and anything newer.
+                        _ => {}
+                    }
+                }
+                _ = ticker.tick() => {
+                    let hours = interval.as_secs_f64() * self.args.time_scale / 3600.0;
+                    let hour = self.clock.hour();
+                    self.room.step(hours, outside_temperature(hour), &mut self.rng);
+
+                    seq += 1;
+                    let temp = json!({
+                        "device_id": self.id,
+                        "seq": seq,
+                        "type": "temp",
+                        "data": {
+                            "temp": (self.room.temperature * 10.0).round() / 10.0,
+                            "hum": self.room.humidity.round(),
+                            "wh": self.room.watthour.round(),
+                        },
+                    });
+                    socket.send(Message::Text(temp.to_string())).await?;
+                    self.stats.sent.fetch_add(1, Ordering::Relaxed);
+
+                    // Motion is a Poisson process; the sensor stays quiet while
+                    // presence is still being reported.
+                    let moved = self.rng.gen::<f64>() < 1.0 - (-motion_rate(hour) * hours).exp();
+                    let quiet = last_move.is_some_and(|t| t.elapsed() < presence_timeout / 2);
+                    if moved && !quiet {
+                        seq += 1;
+                        let msg = json!({ "device_id": self.id, "seq": seq, "type": "move" });
+                        socket.send(Message::Text(msg.to_string())).await?;
+                        self.stats.sent.fetch_add(1, Ordering::Relaxed);
+                        last_move = Some(Instant::now());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn report_heater<S>(&mut self, socket: &mut S, seq: &mut i64) -> anyhow::Result<()>
+    where
+        S: SinkExt<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+    {
+        *seq += 1;
+        let msg = json!({
+            "device_id": self.id,
+            "seq": seq,
+            "type": "state",
+            "data": {
+                "channel": HEATER_CHANNEL,
+                "type": "relay",
+                "value": if self.room.heating { 1 } else { 0 },
+            },
+        });
+        socket.send(Message::Text(msg.to_string())).await?;
+        self.stats.sent.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Pretends to download and flash the image, then reboots into it.
+    async fn update<S>(&mut self, socket: &mut S, seq: &mut i64, version: String) -> anyhow::Result<()>
+    where
+        S: SinkExt<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+    {
+        println!("Device {} updating to {}", self.id, version);
+        for (status, progress) in [("downloading", 0), ("downloading", 50), ("installing", 100)] {
+            *seq += 1;
+            let msg = json!({
+                "device_id": self.id,
+                "seq": seq,
+                "type": "update_status",
+                "data": { "version": version, "status": status, "progress": progress },
+            });
+            socket.send(Message::Text(msg.to_string())).await?;
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+
+        self.firmware_version = version;
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Arc::new(Args::parse());
+    let tokens = Arc::new(Tokens::load(args.tokens.clone()));
+    let stats = Arc::new(Stats::default());
+    let clock = Arc::new(Clock::new(args.time_scale));
+
+    for i in 0..args.count {
+        let id = args.first_id + i as i64;
+        let mut rng = StdRng::seed_from_u64(args.seed.wrapping_add(id as u64));
+        let device = Device {
+            id,
+            args: args.clone(),
+            tokens: tokens.clone(),
+            stats: stats.clone(),
+            clock: clock.clone(),
+            room: Room::new(&mut rng),
+            rng,
+            firmware_version: FIRMWARE_VERSION.to_string(),
+        };
+        tokio::spawn(device.run());
+    }
+
+    let mut report = tokio::time::interval(Duration::from_secs(10));
+    report.tick().await;
+    loop {
+        report.tick().await;
+        println!(
+            "{} of {} devices connected, {} messages sent, {} received",
+            stats.connected.load(Ordering::Relaxed),
+            args.count,
+            stats.sent.load(Ordering::Relaxed),
+            stats.received.load(Ordering::Relaxed),
+        );
+    }
+}
@@ -0,0 +1,544 @@
+use crate::utils::config::env_or;
+use crate::utils::jwt::JWTAuth;
+use axum::extract::{Path, Query};
+use axum::http::{HeaderValue, StatusCode};
+use axum::routing::{delete, get, post};
+use axum::{Extension, Json, Router};
+use http::header::{CONTENT_TYPE, COOKIE};
+use http::Method;
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+use std::collections::HashMap;
+use tower_http::cors::CorsLayer;
+
+/// Sanity limits and linear correction for one metric of a room or device.
+#[derive(Debug, Clone)]
+pub struct Calibration {
+    pub min_value: Option<f64>,
+    pub max_value: Option<f64>,
+    /// Largest change from the previous accepted value before a reading is
+    /// treated as a spike.
+    pub max_step: Option<f64>,
+    pub offset: f64,
+    pub scale: f64,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            min_value: None,
+            max_value: None,
+            max_step: None,
+            offset: 0.0,
+            scale: 1.0,
+        }
+    }
+}
+
+/// Outcome of checking one reading against its calibration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Accepted(f64),
+    /// Not a number or outside the valid range.
+    Invalid,
+    /// Valid, but too far from the previous accepted value.
+    Spike(f64),
+}
+
+impl Calibration {
+    /// Corrects the reading and checks it against the limits.
+    pub fn check(&self, raw: f64, previous: Option<f64>) -> Verdict {
+        let value = raw * self.scale + self.offset;
+        if !value.is_finite() {
+            return Verdict::Invalid;
+        }
+        if self.min_value.is_some_and(|min| value < min) || self.max_value.is_some_and(|max| value > max) {
+            return Verdict::Invalid;
+        }
+        if let (Some(max_step), Some(previous)) = (self.max_step, previous) {
+            if (value - previous).abs() > max_step {
+                return Verdict::Spike(value);
+            }
+        }
+
+        Verdict::Accepted(value)
+    }
+}
+
+/// Calibrations in effect for one device, keyed by metric name. Device level
+/// entries override the ones of its room.
+#[derive(Debug, Clone, Default)]
+pub struct Calibrations {
+    device_id: i64,
+    calibrations: HashMap<String, Calibration>,
+    /// Spikes in a row per metric.
+    streaks: HashMap<String, i64>,
+}
+
+impl Calibrations {
+    pub async fn load(conn: &mut SqliteConnection, device_id: i64) -> anyhow::Result<Self> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT metric, min_value, max_value, max_step, value_offset, value_scale
+                FROM calibration
+                WHERE device_id = ? OR room_id = (SELECT room_id FROM device WHERE device_id = ?)
+                ORDER BY device_id IS NOT NULL
+            "#,
+            device_id,
+            device_id,
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut calibrations = HashMap::new();
+        for row in rows {
+            calibrations.insert(
+                row.metric,
+                Calibration {
+                    min_value: row.min_value,
+                    max_value: row.max_value,
+                    max_step: row.max_step,
+                    offset: row.value_offset,
+                    scale: row.value_scale,
+                },
+            );
+        }
+
+        let streaks = sqlx::query!(
+            "SELECT metric, rejected FROM spike_streak WHERE device_id = ?",
+            device_id
+        )
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .map(|row| (row.metric, row.rejected))
+        .collect();
+
+        Ok(Calibrations {
+            device_id,
+            calibrations,
+            streaks,
+        })
+    }
+
+    /// Returns the corrected value, or `None` if the reading has to be dropped.
+    /// A real change larger than `max_step`, e.g. after the sensor was moved,
+    /// is accepted once `SPIKE_CONFIRMATIONS` readings in a row were rejected
+    /// as spikes.
+    pub fn apply(&mut self, metric: &str, raw: f64, previous: Option<f64>) -> Option<f64> {
+        let verdict = match self.calibrations.get(metric) {
+            Some(calibration) => calibration.check(raw, previous),
+            None => Calibration::default().check(raw, previous),
+        };
+
+        match verdict {
+            Verdict::Accepted(value) => {
+                self.streaks.insert(metric.to_string(), 0);
+                Some(value)
+            }
+            Verdict::Invalid => None,
+            Verdict::Spike(value) => {
+                let rejected = self.streaks.entry(metric.to_string()).or_insert(0);
+                *rejected += 1;
+                if *rejected < env_or("SPIKE_CONFIRMATIONS", 3i64) {
+                    return None;
+                }
+                *rejected = 0;
+                Some(value)
+            }
+        }
+    }
+
+    /// Stores the spike streaks for the next readings of the device.
+    pub async fn save(&self, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        for (metric, rejected) in &self.streaks {
+            sqlx::query!(
+                r#"
+                INSERT INTO spike_streak(device_id, metric, rejected) VALUES (?, ?, ?)
+                    ON CONFLICT(device_id, metric) DO UPDATE SET rejected = excluded.rejected
+                "#,
+                self.device_id,
+                metric,
+                rejected,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CalibrationFilter {
+    room_id: Option<i64>,
+    device_id: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SetCalibrationDto {
+    room_id: Option<i64>,
+    device_id: Option<i64>,
+    metric: String,
+    min_value: Option<f64>,
+    max_value: Option<f64>,
+    max_step: Option<f64>,
+    offset: Option<f64>,
+    scale: Option<f64>,
+    /// Recomputes already stored values from their raw readings.
+    #[serde(default)]
+    retroactive: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CalibrationEntry {
+    id: i64,
+    room_id: Option<i64>,
+    device_id: Option<i64>,
+    metric: String,
+    min_value: Option<f64>,
+    max_value: Option<f64>,
+    max_step: Option<f64>,
+    offset: f64,
+    scale: f64,
+}
+
+pub fn router(pool: SqlitePool) -> Router {
+    Router::new()
+        .route("/", get(get_calibrations_controller))
+        .route("/", post(set_calibration_controller))
+        .route("/:id", delete(delete_calibration_controller))
+        .layer(
+            CorsLayer::new()
+                .allow_origin("http://localhost:5173".parse::<HeaderValue>().unwrap())
+                .allow_credentials(true)
+                .allow_headers([COOKIE, CONTENT_TYPE])
+                .allow_methods([Method::GET, Method::POST, Method::DELETE]),
+        )
+        .layer(Extension(pool))
+}
+
+async fn get_calibrations_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Query(filter): Query<CalibrationFilter>,
+) -> Result<Json<Vec<CalibrationEntry>>, (StatusCode, String)> {
+    let res = get_calibrations_service(pool, jwt_auth.id, filter)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn get_calibrations_service(
+    pool: SqlitePool,
+    user_id: u32,
+    filter: CalibrationFilter,
+) -> anyhow::Result<Vec<CalibrationEntry>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT * FROM calibration
+            WHERE owner_id = ?
+            AND (? IS NULL OR room_id = ?)
+            AND (? IS NULL OR device_id = ?)
+        "#,
+        user_id,
+        filter.room_id,
+        filter.room_id,
+        filter.device_id,
+        filter.device_id,
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let mut res = vec![];
+
+    for row in rows {
+        res.push(CalibrationEntry {
+            id: row.calibration_id,
+            room_id: row.room_id,
+            device_id: row.device_id,
+            metric: row.metric,
+            min_value: row.min_value,
+            max_value: row.max_value,
+            max_step: row.max_step,
+            offset: row.value_offset,
+            scale: row.value_scale,
+        });
+    }
+
+    Ok(res)
+}
+
+async fn set_calibration_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Json(set_calibration_dto): Json<SetCalibrationDto>,
+) -> Result<Json<CalibrationEntry>, (StatusCode, String)> {
+    let res = set_calibration_service(pool, jwt_auth.id, set_calibration_dto)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn set_calibration_service(
+    pool: SqlitePool,
+    user_id: u32,
+    dto: SetCalibrationDto,
+) -> anyhow::Result<CalibrationEntry> {
+    let mut tx = pool.begin().await?;
+    let owned = match (dto.room_id, dto.device_id) {
+        (Some(room_id), None) => sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM room WHERE room_id = ? AND owner_id = ?",
+            room_id,
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?,
+        (None, Some(device_id)) => sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM device WHERE device_id = ? AND owner_id = ?",
+            device_id,
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?,
+        _ => return Err(anyhow::Error::msg("Exactly one of room_id and device_id is required")),
+    };
+    if owned == 0 {
+        return Err(anyhow::Error::msg("Room or device not found"));
+    }
+
+    // Readings are calibrated by their normalized name.
+    let metric = crate::metric::normalize_name(&dto.metric);
+    let known = sqlx::query_scalar!("SELECT COUNT(*) FROM metric_type WHERE name = ?", metric)
+        .fetch_one(&mut *tx)
+        .await?;
+    if known == 0 {
+        return Err(anyhow::Error::msg("Unknown metric"));
+    }
+
+    sqlx::query!(
+        "DELETE FROM calibration WHERE metric = ? AND (room_id = ? OR device_id = ?)",
+        metric,
+        dto.room_id,
+        dto.device_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let offset = dto.offset.unwrap_or(0.0);
+    let scale = dto.scale.unwrap_or(1.0);
+    let res = sqlx::query!(
+        r#"
+        INSERT INTO calibration(owner_id, room_id, device_id, metric, min_value, max_value, max_step, value_offset, value_scale)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING *
+        "#,
+        user_id,
+        dto.room_id,
+        dto.device_id,
+        metric,
+        dto.min_value,
+        dto.max_value,
+        dto.max_step,
+        offset,
+        scale,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if dto.retroactive {
+        recalibrate(&mut tx, dto.room_id, dto.device_id, &metric, offset, scale).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(CalibrationEntry {
+        id: res.calibration_id,
+        room_id: res.room_id,
+        device_id: res.device_id,
+        metric: res.metric,
+        min_value: res.min_value,
+        max_value: res.max_value,
+        max_step: res.max_step,
+        offset: res.value_offset,
+        scale: res.value_scale,
+    })
+}
+
+/// Recomputes stored values of `metric` from their raw readings. A room level
+/// calibration skips devices that have their own.
+async fn recalibrate(
+    conn: &mut SqliteConnection,
+    room_id: Option<i64>,
+    device_id: Option<i64>,
+    metric: &str,
+    offset: f64,
+    scale: f64,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE metric_sample SET value = raw_value * ? + ?
+            WHERE raw_value IS NOT NULL
+            AND metric_id = (SELECT metric_id FROM metric_type WHERE name = ?)
+            AND (device_id = ? OR (room_id = ? AND NOT EXISTS (
+                SELECT 1 FROM calibration c WHERE c.device_id = metric_sample.device_id AND c.metric = ?
+            )))
+        "#,
+        scale,
+        offset,
+        metric,
+        device_id,
+        room_id,
+        metric,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    // The legacy history table keeps one column per built-in metric.
+    match metric {
+        "temperature" => {
+            sqlx::query!(
+                r#"
+                UPDATE room_history SET temperature = raw_temperature * ? + ?
+                    WHERE raw_temperature IS NOT NULL
+                    AND (device_id = ? OR (room_id = ? AND NOT EXISTS (
+                        SELECT 1 FROM calibration c WHERE c.device_id = room_history.device_id AND c.metric = ?
+                    )))
+                "#,
+                scale,
+                offset,
+                device_id,
+                room_id,
+                metric,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        "humidity" => {
+            sqlx::query!(
+                r#"
+                UPDATE room_history SET humidity = raw_humidity * ? + ?
+                    WHERE raw_humidity IS NOT NULL
+                    AND (device_id = ? OR (room_id = ? AND NOT EXISTS (
+                        SELECT 1 FROM calibration c WHERE c.device_id = room_history.device_id AND c.metric = ?
+                    )))
+                "#,
+                scale,
+                offset,
+                device_id,
+                room_id,
+                metric,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        "watthour" => {
+            sqlx::query!(
+                r#"
+                UPDATE room_history SET watthour = raw_watthour * ? + ?
+                    WHERE raw_watthour IS NOT NULL
+                    AND (device_id = ? OR (room_id = ? AND NOT EXISTS (
+                        SELECT 1 FROM calibration c WHERE c.device_id = room_history.device_id AND c.metric = ?
+                    )))
+                "#,
+                scale,
+                offset,
+                device_id,
+                room_id,
+                metric,
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+async fn delete_calibration_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Path(id): Path<i64>,
+) -> Result<String, (StatusCode, String)> {
+    delete_calibration_service(pool, jwt_auth.id, id)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok("Successfully deleted".to_string())
+}
+
+async fn delete_calibration_service(pool: SqlitePool, user_id: u32, id: i64) -> anyhow::Result<()> {
+    sqlx::query!(
+        "DELETE FROM calibration WHERE owner_id = ? AND calibration_id = ?",
+        user_id,
+        id,
+    )
+    .execute(&pool)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibration() -> Calibration {
+        Calibration {
+            min_value: Some(-30.0),
+            max_value: Some(60.0),
+            max_step: Some(5.0),
+            offset: 0.5,
+            scale: 2.0,
+        }
+    }
+
+    #[test]
+    fn corrects_and_checks_readings() {
+        let calibration = calibration();
+        assert_eq!(calibration.check(10.0, None), Verdict::Accepted(20.5));
+        assert_eq!(calibration.check(10.0, Some(18.0)), Verdict::Accepted(20.5));
+        assert_eq!(calibration.check(10.0, Some(10.0)), Verdict::Spike(20.5));
+        assert_eq!(calibration.check(40.0, None), Verdict::Invalid);
+        assert_eq!(calibration.check(-20.0, None), Verdict::Invalid);
+        assert_eq!(calibration.check(f64::NAN, None), Verdict::Invalid);
+        assert_eq!(Calibration::default().check(1000.0, Some(0.0)), Verdict::Accepted(1000.0));
+    }
+
+    #[test]
+    fn accepts_change_after_spike_streak() {
+        let mut calibrations = Calibrations::default();
+        calibrations.calibrations.insert("temperature".to_string(), calibration());
+
+        assert_eq!(calibrations.apply("temperature", 10.0, Some(20.0)), Some(20.5));
+        assert_eq!(calibrations.apply("temperature", 20.0, Some(20.5)), None);
+        assert_eq!(calibrations.apply("temperature", 20.0, Some(20.5)), None);
+        assert_eq!(calibrations.apply("temperature", 20.0, Some(20.5)), Some(40.5));
+        assert_eq!(calibrations.streaks["temperature"], 0);
+    }
+
+    #[test]
+    fn accepted_reading_ends_spike_streak() {
+        let mut calibrations = Calibrations::default();
+        calibrations.calibrations.insert("temperature".to_string(), calibration());
+
+        assert_eq!(calibrations.apply("temperature", 20.0, Some(20.5)), None);
+        assert_eq!(calibrations.apply("temperature", 20.0, Some(20.5)), None);
+        assert_eq!(calibrations.apply("temperature", 10.0, Some(20.5)), Some(20.5));
+        assert_eq!(calibrations.apply("temperature", 20.0, Some(20.5)), None);
+        // Other metrics keep their own streak.
+        assert_eq!(calibrations.apply("humidity", 99.0, Some(40.0)), Some(99.0));
+        assert_eq!(calibrations.streaks["temperature"], 1);
+    }
+}
@@ -0,0 +1,63 @@
+use crate::esp_websockets::WsOutputData;
+use crate::ingest::now_ms;
+use sqlx::{SqliteExecutor, SqlitePool};
+
+/// Current time for the device, with the timezone of its owner's home so it
+/// can schedule in local time.
+pub async fn time_message(pool: &SqlitePool, device_id: i64) -> WsOutputData {
+    WsOutputData::Time {
+        epoch_ms: now_ms(),
+        utc_offset_minutes: utc_offset_minutes(pool, device_id).await,
+    }
+}
+
+/// Timezone offset of the device's owner, UTC for unknown devices.
+pub async fn utc_offset_minutes<'e, E: SqliteExecutor<'e>>(executor: E, device_id: i64) -> i32 {
+    let offset = sqlx::query_scalar!(
+        r#"
+        SELECT u.utc_offset_minutes
+            FROM device d
+            JOIN user u ON u.user_id = d.owner_id
+            WHERE d.device_id = ?
+        "#,
+        device_id
+    )
+    .fetch_optional(executor)
+    .await;
+
+    match offset {
+        Ok(Some(offset)) => offset as i32,
+        _ => 0,
+    }
+}
+
+pub async fn user_utc_offset_minutes(pool: &SqlitePool, user_id: u32) -> i32 {
+    let offset = sqlx::query_scalar!(
+        "SELECT utc_offset_minutes FROM user WHERE user_id = ?",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await;
+
+    match offset {
+        Ok(Some(offset)) => offset as i32,
+        _ => 0,
+    }
+}
+
+/// Offsets in use range from UTC-12:00 to UTC+14:00.
+pub fn is_valid_offset(utc_offset_minutes: i32) -> bool {
+    (-12 * 60..=14 * 60).contains(&utc_offset_minutes)
+}
+
+pub async fn set_utc_offset(pool: &SqlitePool, user_id: u32, utc_offset_minutes: i32) -> anyhow::Result<()> {
+    sqlx::query!(
+        "UPDATE user SET utc_offset_minutes = ? WHERE user_id = ?",
+        utc_offset_minutes,
+        user_id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,747 @@
+use crate::codec::Codec;
+use crate::events::{self, DeviceEvent};
+use crate::hub::{Connection, DeviceHub};
+use crate::telemetry;
+use crate::utils::config::env_or;
+use axum::http::StatusCode;
+use sqlx::SqlitePool;
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+const OPTION_OBSERVE: u16 = 6;
+const OPTION_URI_PATH: u16 = 11;
+const OPTION_CONTENT_FORMAT: u16 = 12;
+const OPTION_URI_QUERY: u16 = 15;
+const OPTION_ACCEPT: u16 = 17;
+
+const GET: u8 = 0x01;
+const POST: u8 = 0x02;
+const CHANGED: u8 = 0x44;
+const CONTENT: u8 = 0x45;
+const BAD_REQUEST: u8 = 0x80;
+const UNAUTHORIZED: u8 = 0x81;
+const NOT_FOUND: u8 = 0x84;
+const METHOD_NOT_ALLOWED: u8 = 0x85;
+const UNSUPPORTED_CONTENT_FORMAT: u8 = 0x8F;
+const INTERNAL_SERVER_ERROR: u8 = 0xA0;
+
+/// Number of answered confirmable requests kept to answer retransmissions.
+const RECENT_RESPONSES: usize = 64;
+/// Number of notifications per observation a Reset can still refer to.
+const RECENT_NOTIFICATIONS: usize = 16;
+/// Largest datagram accepted, anything longer is dropped instead of being
+/// parsed truncated.
+const MAX_DATAGRAM: usize = 2048;
+
+static NEXT_MESSAGE_ID: AtomicU16 = AtomicU16::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Confirmable,
+    NonConfirmable,
+    Acknowledgement,
+    Reset,
+}
+
+/// CoAP message as defined in RFC 7252, section 3.
+#[derive(Debug, Clone)]
+struct Packet {
+    kind: Kind,
+    code: u8,
+    message_id: u16,
+    token: Vec<u8>,
+    options: Vec<(u16, Vec<u8>)>,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 4 || data[0] >> 6 != 1 {
+            return None;
+        }
+
+        let kind = match (data[0] >> 4) & 0x3 {
+            0 => Kind::Confirmable,
+            1 => Kind::NonConfirmable,
+            2 => Kind::Acknowledgement,
+            _ => Kind::Reset,
+        };
+        let token_length = (data[0] & 0xF) as usize;
+        if token_length > 8 {
+            return None;
+        }
+        let token = data.get(4..4 + token_length)?.to_vec();
+
+        let mut pos = 4 + token_length;
+        let mut number = 0u16;
+        let mut options = vec![];
+        let mut payload = vec![];
+        while pos < data.len() {
+            if data[pos] == 0xFF {
+                payload = data[pos + 1..].to_vec();
+                if payload.is_empty() {
+                    return None;
+                }
+                break;
+            }
+
+            let delta = data[pos] >> 4;
+            let length = data[pos] & 0xF;
+            pos += 1;
+            let delta = read_extended(data, &mut pos, delta)?;
+            let length = read_extended(data, &mut pos, length)? as usize;
+            number = number.checked_add(delta)?;
+            options.push((number, data.get(pos..pos + length)?.to_vec()));
+            pos += length;
+        }
+
+        Some(Packet {
+            kind,
+            code: data[1],
+            message_id: u16::from_be_bytes([data[2], data[3]]),
+            token,
+            options,
+            payload,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let kind = match self.kind {
+            Kind::Confirmable => 0,
+            Kind::NonConfirmable => 1,
+            Kind::Acknowledgement => 2,
+            Kind::Reset => 3,
+        };
+
+        let mut res = vec![0x40 | kind << 4 | self.token.len() as u8, self.code];
+        res.extend(self.message_id.to_be_bytes());
+        res.extend(&self.token);
+
+        let mut options = self.options.clone();
+        options.sort_by_key(|(number, _)| *number);
+        let mut last = 0;
+        for (number, value) in options {
+            let (delta, delta_ext) = extended(number - last);
+            let (length, length_ext) = extended(value.len() as u16);
+            res.push(delta << 4 | length);
+            res.extend(delta_ext);
+            res.extend(length_ext);
+            res.extend(value);
+            last = number;
+        }
+
+        if !self.payload.is_empty() {
+            res.push(0xFF);
+            res.extend(&self.payload);
+        }
+
+        res
+    }
+
+    /// Answers a request: piggybacked on the acknowledgement of confirmable
+    /// requests, as a separate message otherwise.
+    fn response(&self, code: u8, options: Vec<(u16, Vec<u8>)>, payload: Vec<u8>) -> Packet {
+        let (kind, message_id) = match self.kind {
+            Kind::Confirmable => (Kind::Acknowledgement, self.message_id),
+            _ => (Kind::NonConfirmable, next_message_id()),
+        };
+
+        Packet {
+            kind,
+            code,
+            message_id,
+            token: self.token.clone(),
+            options,
+            payload,
+        }
+    }
+
+    fn strings(&self, number: u16) -> Vec<&str> {
+        self.options
+            .iter()
+            .filter(|(n, _)| *n == number)
+            .filter_map(|(_, value)| std::str::from_utf8(value).ok())
+            .collect()
+    }
+
+    fn uint(&self, number: u16) -> Option<u32> {
+        let (_, value) = self.options.iter().find(|(n, _)| *n == number)?;
+        if value.len() > 4 {
+            return None;
+        }
+
+        Some(value.iter().fold(0, |acc, b| acc << 8 | *b as u32))
+    }
+
+    fn query(&self, key: &str) -> Option<&str> {
+        self.strings(OPTION_URI_QUERY)
+            .into_iter()
+            .filter_map(|query| query.split_once('='))
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| value)
+    }
+}
+
+fn read_extended(data: &[u8], pos: &mut usize, nibble: u8) -> Option<u16> {
+    match nibble {
+        13 => {
+            let value = *data.get(*pos)? as u16 + 13;
+            *pos += 1;
+            Some(value)
+        }
+        14 => {
+            let value = u16::from_be_bytes([*data.get(*pos)?, *data.get(*pos + 1)?]);
+            *pos += 2;
+            value.checked_add(269)
+        }
+        15 => None,
+        nibble => Some(nibble as u16),
+    }
+}
+
+fn extended(value: u16) -> (u8, Vec<u8>) {
+    match value {
+        0..=12 => (value as u8, vec![]),
+        13..=268 => (13, vec![(value - 13) as u8]),
+        _ => (14, (value - 269).to_be_bytes().to_vec()),
+    }
+}
+
+/// Shortest big-endian encoding, as used by uint options.
+fn uint(value: u32) -> Vec<u8> {
+    value
+        .to_be_bytes()
+        .into_iter()
+        .skip_while(|b| *b == 0)
+        .collect()
+}
+
+/// Codec of a Content-Format. MessagePack has no registered format.
+fn format_codec(format: u32) -> Option<Codec> {
+    match format {
+        50 => Some(Codec::Json),
+        60 => Some(Codec::Cbor),
+        _ => None,
+    }
+}
+
+fn content_format(codec: Codec) -> Option<u32> {
+    match codec {
+        Codec::Json => Some(50),
+        Codec::Cbor => Some(60),
+        Codec::MessagePack => None,
+    }
+}
+
+fn next_message_id() -> u16 {
+    NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+fn status_code(status: StatusCode) -> u8 {
+    match status {
+        StatusCode::BAD_REQUEST => BAD_REQUEST,
+        StatusCode::UNAUTHORIZED => UNAUTHORIZED,
+        StatusCode::NOT_FOUND => NOT_FOUND,
+        _ => INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Device observing its command resource.
+struct Observer {
+    addr: SocketAddr,
+    token: Vec<u8>,
+    device_id: i64,
+    generation: u64,
+    last_seen: Instant,
+    /// Message ids of the latest notifications, shared with `notify`.
+    notifications: Arc<Mutex<VecDeque<u16>>>,
+}
+
+impl Observer {
+    /// Whether a Reset from `addr` rejects this observation. Resets echo the
+    /// message id of the notification, and usually carry no token.
+    fn reset_by(&self, addr: SocketAddr, req: &Packet) -> bool {
+        self.addr == addr
+            && ((!req.token.is_empty() && self.token == req.token)
+                || self.notifications.lock().unwrap().contains(&req.message_id))
+    }
+}
+
+struct Server {
+    socket: Arc<UdpSocket>,
+    pool: SqlitePool,
+    hub: DeviceHub,
+    observers: Vec<Observer>,
+    recent: VecDeque<((SocketAddr, u16), Vec<u8>)>,
+    timeout: Duration,
+}
+
+/// CoAP endpoint for nodes too constrained even for HTTP, on `COAP_PORT`.
+///
+/// - `POST /telemetry/{device_id}?token=...` takes the same messages as the
+///   WebSocket and answers like `POST /device/:id/telemetry`.
+/// - `GET /commands/{device_id}?token=...` returns the pending messages. With
+///   Observe, every later command is delivered as a notification.
+///
+/// JSON (Content-Format 50) and CBOR (60) are accepted, JSON is the default.
+/// Notifications are non-confirmable, so an observation is dropped once the
+/// device has sent no request for `COAP_OBSERVE_TIMEOUT_MS`.
+pub async fn run(pool: SqlitePool, hub: DeviceHub) {
+    let port: u16 = env_or("COAP_PORT", 5683);
+    let socket = match UdpSocket::bind(("0.0.0.0", port)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("Failed to start CoAP server on port {}: {}", port, e);
+            return;
+        }
+    };
+    println!("CoAP server is listening on port {}!", port);
+
+    let mut server = Server {
+        socket: Arc::new(socket),
+        pool,
+        hub,
+        observers: vec![],
+        recent: VecDeque::new(),
+        timeout: Duration::from_millis(env_or("COAP_OBSERVE_TIMEOUT_MS", 300000u64).max(1)),
+    };
+    let socket = server.socket.clone();
+    let mut sweep = tokio::time::interval(server.timeout);
+    // One byte more than accepted, to tell a full datagram from a truncated one.
+    let mut buf = [0u8; MAX_DATAGRAM + 1];
+
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = sweep.tick() => {
+                let deadline = Instant::now() - server.timeout;
+                server.cancel(|o| o.last_seen < deadline);
+                continue;
+            }
+        };
+        let (len, addr) = match received {
+            Ok(res) => res,
+            Err(_) => continue,
+        };
+        if len > MAX_DATAGRAM {
+            println!("Dropping CoAP datagram from {} larger than {} bytes", addr, MAX_DATAGRAM);
+            continue;
+        }
+        let Some(req) = Packet::parse(&buf[..len]) else {
+            continue;
+        };
+
+        match req.kind {
+            Kind::Reset => {
+                // The device no longer wants these notifications.
+                server.cancel(|o| o.reset_by(addr, &req));
+                continue;
+            }
+            Kind::Acknowledgement => continue,
+            _ => {}
+        }
+
+        let key = (addr, req.message_id);
+        if req.kind == Kind::Confirmable {
+            if let Some((_, res)) = server.recent.iter().find(|(k, _)| *k == key) {
+                _ = server.socket.send_to(res, addr).await;
+                continue;
+            }
+        }
+
+        let res = server.handle(&req, addr).await.encode();
+        _ = server.socket.send_to(&res, addr).await;
+
+        if req.kind == Kind::Confirmable {
+            if server.recent.len() >= RECENT_RESPONSES {
+                server.recent.pop_front();
+            }
+            server.recent.push_back((key, res));
+        }
+    }
+}
+
+impl Server {
+    async fn handle(&mut self, req: &Packet, addr: SocketAddr) -> Packet {
+        let path = req.strings(OPTION_URI_PATH);
+        let (resource, device_id) = match path.as_slice() {
+            [resource, device_id] => match device_id.parse::<i64>() {
+                Ok(device_id) => (*resource, device_id),
+                Err(_) => return req.response(NOT_FOUND, vec![], vec![]),
+            },
+            _ => return req.response(NOT_FOUND, vec![], vec![]),
+        };
+
+        match (req.code, resource) {
+            (POST, "telemetry") => self.telemetry(req, device_id).await,
+            (GET, "commands") => self.commands(req, addr, device_id).await,
+            (_, "telemetry") | (_, "commands") => req.response(METHOD_NOT_ALLOWED, vec![], vec![]),
+            _ => req.response(NOT_FOUND, vec![], vec![]),
+        }
+    }
+
+    async fn telemetry(&mut self, req: &Packet, device_id: i64) -> Packet {
+        let codec = match req.uint(OPTION_CONTENT_FORMAT) {
+            Some(format) => match format_codec(format) {
+                Some(codec) => codec,
+                None => return req.response(UNSUPPORTED_CONTENT_FORMAT, vec![], vec![]),
+            },
+            None => Codec::Json,
+        };
+
+        let token = req.query("token");
+        match telemetry::handle_telemetry(&self.pool, &self.hub, device_id, token, codec, &req.payload).await {
+            Ok(res) => {
+                self.seen(device_id);
+                encoded(req, CHANGED, vec![], codec, &res)
+            }
+            Err((status, reason)) => req.response(status_code(status), vec![], reason.into_bytes()),
+        }
+    }
+
+    async fn commands(&mut self, req: &Packet, addr: SocketAddr, device_id: i64) -> Packet {
+        let codec = match req.uint(OPTION_ACCEPT) {
+            Some(format) => match format_codec(format) {
+                Some(codec) => codec,
+                None => return req.response(UNSUPPORTED_CONTENT_FORMAT, vec![], vec![]),
+            },
+            None => Codec::Json,
+        };
+
+        if let Err((status, reason)) =
+            telemetry::authenticate(&self.pool, device_id, req.query("token")).await
+        {
+            return req.response(status_code(status), vec![], reason.into_bytes());
+        }
+        self.seen(device_id);
+
+        let pending = telemetry::pending_messages(&self.pool, device_id).await;
+        match req.uint(OPTION_OBSERVE) {
+            // Without the Observe option the client knows it was not registered.
+            Some(0) if self.observe(req, addr, device_id, codec).await => {
+                encoded(req, CONTENT, vec![(OPTION_OBSERVE, uint(0))], codec, &pending)
+            }
+            Some(1) => {
+                self.cancel(|o| o.addr == addr && o.token == req.token);
+                encoded(req, CONTENT, vec![], codec, &pending)
+            }
+            _ => encoded(req, CONTENT, vec![], codec, &pending),
+        }
+    }
+
+    /// Registers the observer as a connection of the device. Earlier
+    /// observations of the device are dropped. Returns `false` if the hub
+    /// refused the connection.
+    async fn observe(&mut self, req: &Packet, addr: SocketAddr, device_id: i64, codec: Codec) -> bool {
+        let was_connected = self.hub.is_connected(device_id);
+        for offline in self.remove(|o| o.device_id == device_id || (o.addr == addr && o.token == req.token)) {
+            if offline != device_id {
+                events::emit(DeviceEvent::Offline { device_id: offline });
+            }
+        }
+
+        let Ok(connection) = self.hub.register(device_id) else {
+            if was_connected && !self.hub.is_connected(device_id) {
+                events::emit(DeviceEvent::Offline { device_id });
+            }
+            return false;
+        };
+        if !was_connected {
+            events::emit(DeviceEvent::Online { device_id });
+        }
+        let notifications = Arc::new(Mutex::new(VecDeque::new()));
+        self.observers.push(Observer {
+            addr,
+            token: req.token.clone(),
+            device_id,
+            generation: connection.generation,
+            last_seen: Instant::now(),
+            notifications: notifications.clone(),
+        });
+
+        let capabilities = telemetry::capabilities(&self.pool, device_id).await;
+        tokio::spawn(notify(
+            self.socket.clone(),
+            addr,
+            req.token.clone(),
+            codec,
+            capabilities,
+            notifications,
+            connection,
+        ));
+        true
+    }
+
+    fn seen(&mut self, device_id: i64) {
+        for observer in self.observers.iter_mut().filter(|o| o.device_id == device_id) {
+            observer.last_seen = Instant::now();
+        }
+    }
+
+    /// Drops the matching observations and reports the devices left offline.
+    fn cancel<F: Fn(&Observer) -> bool>(&mut self, filter: F) {
+        for device_id in self.remove(filter) {
+            events::emit(DeviceEvent::Offline { device_id });
+        }
+    }
+
+    /// Drops the matching observations, returning the devices whose last
+    /// connection they were.
+    fn remove<F: Fn(&Observer) -> bool>(&mut self, filter: F) -> Vec<i64> {
+        let hub = &self.hub;
+        let mut offline = vec![];
+        self.observers.retain(|o| {
+            if !filter(o) {
+                return true;
+            }
+
+            if hub.unregister(o.device_id, o.generation) {
+                offline.push(o.device_id);
+            }
+            false
+        });
+        offline
+    }
+}
+
+fn encoded<T: serde::Serialize>(
+    req: &Packet,
+    code: u8,
+    mut options: Vec<(u16, Vec<u8>)>,
+    codec: Codec,
+    value: &T,
+) -> Packet {
+    match codec.encode(value) {
+        Ok(payload) => {
+            if let Some(format) = content_format(codec) {
+                options.push((OPTION_CONTENT_FORMAT, uint(format)));
+            }
+            req.response(code, options, payload)
+        }
+        Err(_) => req.response(INTERNAL_SERVER_ERROR, vec![], vec![]),
+    }
+}
+
+/// Sends every command queued for the device as a non-confirmable
+/// notification of its observation.
+async fn notify(
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+    token: Vec<u8>,
+    codec: Codec,
+    capabilities: HashSet<String>,
+    notifications: Arc<Mutex<VecDeque<u16>>>,
+    mut connection: Connection,
+) {
+    let mut sequence: u32 = 1;
+
+    while let Some(msg) = connection.recv().await {
+        if let Some(capability) = msg.required_capability() {
+            if !capabilities.contains(capability) {
+                continue;
+            }
+        }
+
+        let Ok(payload) = codec.encode(&msg) else {
+            continue;
+        };
+        let mut options = vec![(OPTION_OBSERVE, uint(sequence))];
+        if let Some(format) = content_format(codec) {
+            options.push((OPTION_CONTENT_FORMAT, uint(format)));
+        }
+
+        let message_id = next_message_id();
+        {
+            let mut notifications = notifications.lock().unwrap();
+            if notifications.len() >= RECENT_NOTIFICATIONS {
+                notifications.pop_front();
+            }
+            notifications.push_back(message_id);
+        }
+        let notification = Packet {
+            kind: Kind::NonConfirmable,
+            code: CONTENT,
+            message_id,
+            token: token.clone(),
+            options,
+            payload,
+        };
+        _ = socket.send_to(&notification.encode(), addr).await;
+
+        // Observe sequence numbers are 24 bits wide.
+        sequence = (sequence + 1) & 0xFF_FFFF;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(options: Vec<(u16, Vec<u8>)>, payload: &[u8]) -> Packet {
+        Packet {
+            kind: Kind::Confirmable,
+            code: POST,
+            message_id: 0x1234,
+            token: vec![0xAB, 0xCD],
+            options,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn parses_header_and_token() {
+        let req = Packet::parse(&[0x52, POST, 0x12, 0x34, 0xAB, 0xCD]).unwrap();
+        assert_eq!(req.kind, Kind::NonConfirmable);
+        assert_eq!(req.code, POST);
+        assert_eq!(req.message_id, 0x1234);
+        assert_eq!(req.token, vec![0xAB, 0xCD]);
+        assert!(req.options.is_empty());
+        assert!(req.payload.is_empty());
+    }
+
+    #[test]
+    fn rejects_short_or_foreign_headers() {
+        assert!(Packet::parse(&[0x40, GET, 0x00]).is_none());
+        // Version 2.
+        assert!(Packet::parse(&[0x80, GET, 0x00, 0x00]).is_none());
+    }
+
+    #[test]
+    fn rejects_token_length_over_8() {
+        let mut data = vec![0x49, GET, 0x00, 0x00];
+        data.extend([0; 9]);
+        assert!(Packet::parse(&data).is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_token() {
+        assert!(Packet::parse(&[0x44, GET, 0x00, 0x00, 0x01, 0x02]).is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_options() {
+        // Uri-Path announcing 5 bytes with 2 present.
+        assert!(Packet::parse(&[0x40, GET, 0x00, 0x00, 0xB5, b'a', b'b']).is_none());
+        // Extended delta without its extension byte.
+        assert!(Packet::parse(&[0x40, GET, 0x00, 0x00, 0xD0]).is_none());
+        // Two byte extended length with one byte present.
+        assert!(Packet::parse(&[0x40, GET, 0x00, 0x00, 0x1E, 0x00]).is_none());
+    }
+
+    #[test]
+    fn parses_extended_option_deltas() {
+        // Delta 13 + 2 = 15 (Uri-Query), then 14 + 0x0001 + 269 = 285.
+        let data = [0x40, GET, 0x00, 0x00, 0xD1, 0x02, b'q', 0xE0, 0x00, 0x01];
+        let req = Packet::parse(&data).unwrap();
+        assert_eq!(req.options, vec![(OPTION_URI_QUERY, b"q".to_vec()), (285, vec![])]);
+    }
+
+    #[test]
+    fn rejects_reserved_delta_and_length() {
+        assert!(Packet::parse(&[0x40, GET, 0x00, 0x00, 0xF1, 0x00]).is_none());
+        assert!(Packet::parse(&[0x40, GET, 0x00, 0x00, 0x1F]).is_none());
+    }
+
+    #[test]
+    fn rejects_option_number_overflow() {
+        assert!(Packet::parse(&[0x40, GET, 0x00, 0x00, 0xE0, 0xFF, 0xFF, 0xE0, 0xFF, 0xFF]).is_none());
+    }
+
+    #[test]
+    fn rejects_payload_marker_without_payload() {
+        assert!(Packet::parse(&[0x40, POST, 0x00, 0x00, 0xFF]).is_none());
+    }
+
+    #[test]
+    fn parses_payload() {
+        let req = Packet::parse(&[0x40, POST, 0x00, 0x00, 0xB1, b't', 0xFF, b'{', b'}']).unwrap();
+        assert_eq!(req.strings(OPTION_URI_PATH), vec!["t"]);
+        assert_eq!(req.payload, b"{}".to_vec());
+    }
+
+    #[test]
+    fn encodes_options_in_order_with_extended_fields() {
+        let res = packet(
+            vec![(OPTION_URI_QUERY, b"token=x".to_vec()), (OPTION_URI_PATH, vec![b'a'; 300])],
+            b"",
+        )
+        .encode();
+
+        assert_eq!(&res[..6], &[0x42, POST, 0x12, 0x34, 0xAB, 0xCD]);
+        // Uri-Path: delta 11, length 300 = 14 + 0x001F + 269.
+        assert_eq!(&res[6..9], &[0xBE, 0x00, 0x1F]);
+        // Uri-Query: delta 4 after the 300 byte value.
+        assert_eq!(&res[309..311], &[0x47, b't']);
+        assert_eq!(res.len(), 311 + 6);
+    }
+
+    #[test]
+    fn encodes_delta_13() {
+        let res = packet(vec![(20, vec![])], b"").encode();
+        assert_eq!(&res[6..], &[0xD0, 0x07]);
+    }
+
+    #[test]
+    fn omits_marker_without_payload() {
+        let res = packet(vec![], b"").encode();
+        assert_eq!(res, vec![0x42, POST, 0x12, 0x34, 0xAB, 0xCD]);
+    }
+
+    #[test]
+    fn round_trips() {
+        let options = vec![
+            (OPTION_OBSERVE, uint(0x01_0203)),
+            (OPTION_URI_PATH, b"telemetry".to_vec()),
+            (OPTION_URI_PATH, b"5".to_vec()),
+            (OPTION_CONTENT_FORMAT, uint(60)),
+            (OPTION_URI_QUERY, b"token=secret".to_vec()),
+            (300, vec![0; 20]),
+            (1000, vec![1; 400]),
+        ];
+        let original = packet(options.clone(), b"payload");
+        let parsed = Packet::parse(&original.encode()).unwrap();
+
+        assert_eq!(parsed.kind, original.kind);
+        assert_eq!(parsed.code, original.code);
+        assert_eq!(parsed.message_id, original.message_id);
+        assert_eq!(parsed.token, original.token);
+        assert_eq!(parsed.options, options);
+        assert_eq!(parsed.payload, b"payload".to_vec());
+        assert_eq!(parsed.uint(OPTION_OBSERVE), Some(0x01_0203));
+        assert_eq!(parsed.strings(OPTION_URI_PATH), vec!["telemetry", "5"]);
+        assert_eq!(parsed.query("token"), Some("secret"));
+    }
+
+    #[test]
+    fn reset_matches_notification_or_token() {
+        let addr: SocketAddr = "10.0.0.5:5683".parse().unwrap();
+        let observer = Observer {
+            addr,
+            token: vec![0xAB, 0xCD],
+            device_id: 5,
+            generation: 1,
+            last_seen: Instant::now(),
+            notifications: Arc::new(Mutex::new(VecDeque::from([7]))),
+        };
+        let reset = |message_id, token: Vec<u8>| Packet {
+            kind: Kind::Reset,
+            code: 0,
+            message_id,
+            token,
+            options: vec![],
+            payload: vec![],
+        };
+
+        assert!(observer.reset_by(addr, &reset(7, vec![])));
+        assert!(observer.reset_by(addr, &reset(8, vec![0xAB, 0xCD])));
+        // Another observation of the same peer.
+        assert!(!observer.reset_by(addr, &reset(8, vec![])));
+        assert!(!observer.reset_by(addr, &reset(8, vec![0x01])));
+        assert!(!observer.reset_by("10.0.0.6:5683".parse().unwrap(), &reset(7, vec![])));
+    }
+}
@@ -0,0 +1,131 @@
+use axum::extract::ws::Message;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Wire format of device frames, negotiated per connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    #[default]
+    Json,
+    Cbor,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl Codec {
+    /// WebSocket subprotocols in order of server preference.
+    pub const SUBPROTOCOLS: [&'static str; 3] = ["smarty.json", "smarty.cbor", "smarty.msgpack"];
+
+    pub fn from_subprotocol(protocol: &str) -> Option<Self> {
+        match protocol {
+            "smarty.json" => Some(Codec::Json),
+            "smarty.cbor" => Some(Codec::Cbor),
+            "smarty.msgpack" => Some(Codec::MessagePack),
+            _ => None,
+        }
+    }
+
+    /// Codec of an HTTP body. Missing or unknown types fall back to JSON.
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        match content_type.and_then(|t| t.split(';').next()).map(str::trim) {
+            Some("application/cbor") => Codec::Cbor,
+            Some("application/msgpack") | Some("application/x-msgpack") => Codec::MessagePack,
+            _ => Codec::Json,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Codec::Json => "application/json",
+            Codec::Cbor => "application/cbor",
+            Codec::MessagePack => "application/msgpack",
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> anyhow::Result<T> {
+        let res = match self {
+            Codec::Json => serde_json::from_slice(data)?,
+            Codec::Cbor => ciborium::from_reader(data)?,
+            Codec::MessagePack => rmp_serde::from_slice(data)?,
+        };
+
+        Ok(res)
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<Vec<u8>> {
+        let res = match self {
+            Codec::Json => serde_json::to_vec(value)?,
+            Codec::Cbor => {
+                let mut buf = vec![];
+                ciborium::into_writer(value, &mut buf)?;
+                buf
+            }
+            // Named encoding keeps struct fields as map keys, which the tagged
+            // protocol enums rely on.
+            Codec::MessagePack => rmp_serde::to_vec_named(value)?,
+        };
+
+        Ok(res)
+    }
+
+    /// Encodes `value` into a WebSocket frame: text for JSON, binary otherwise.
+    pub fn encode_message<T: Serialize>(&self, value: &T) -> anyhow::Result<Message> {
+        let data = self.encode(value)?;
+        let msg = match self {
+            Codec::Json => Message::Text(String::from_utf8(data)?),
+            Codec::Cbor | Codec::MessagePack => Message::Binary(data),
+        };
+
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::esp_websockets::{WsInnerData, WsInputData, WsOutputData};
+    use serde_json::{json, Value};
+
+    const CODECS: [Codec; 3] = [Codec::Json, Codec::Cbor, Codec::MessagePack];
+
+    #[test]
+    fn round_trips_device_frames() {
+        let frame = json!({
+            "device_id": 5,
+            "seq": 7,
+            "type": "temp",
+            "data": {"temp": 21.5, "hum": 40.0, "wh": 3.0}
+        });
+
+        for codec in CODECS {
+            let data = codec.encode(&frame).unwrap();
+            let input: WsInputData = codec.decode(&data).unwrap();
+            assert_eq!(input.device_id, 5);
+            assert_eq!(input.seq, Some(7));
+            assert!(matches!(input.inner, WsInnerData::Temp { temp, .. } if temp == 21.5));
+        }
+    }
+
+    #[test]
+    fn round_trips_server_messages() {
+        let msg = WsOutputData::SetState { channel: 2, value: 0.5 };
+        let expected = json!({"type": "set_state", "data": {"channel": 2, "value": 0.5}});
+
+        for codec in CODECS {
+            let data = codec.encode(&msg).unwrap();
+            assert_eq!(codec.decode::<Value>(&data).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn picks_frame_type_and_content_type() {
+        let msg = WsOutputData::Settings { presence_timeout: 1 };
+        assert!(matches!(Codec::Json.encode_message(&msg).unwrap(), Message::Text(_)));
+        assert!(matches!(Codec::Cbor.encode_message(&msg).unwrap(), Message::Binary(_)));
+
+        assert_eq!(Codec::from_content_type(Some("application/cbor; charset=utf-8")), Codec::Cbor);
+        assert_eq!(Codec::from_content_type(Some("application/x-msgpack")), Codec::MessagePack);
+        assert_eq!(Codec::from_content_type(Some("text/plain")), Codec::Json);
+        assert_eq!(Codec::from_content_type(None), Codec::Json);
+    }
+}
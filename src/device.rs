@@ -0,0 +1,394 @@
+use crate::esp_websockets::WsOutputData;
+use crate::health::{self, HealthIssue};
+use crate::hub::DeviceHub;
+use crate::utils::device_token::{generate_claim_code, generate_token, hash_token};
+use crate::utils::jwt::JWTAuth;
+use axum::extract::Path;
+use axum::http::{HeaderValue, StatusCode};
+use axum::routing::{delete, get, patch, post};
+use axum::{Extension, Json, Router};
+use http::header::{CONTENT_TYPE, COOKIE};
+use http::Method;
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+use std::time::Duration;
+use tower_http::cors::CorsLayer;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ClaimDeviceDto {
+    code: String,
+    name: Option<String>,
+    room_id: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct UpdateDeviceDto {
+    id: i64,
+    name: Option<String>,
+    room_id: Option<i64>,
+    mqtt_enabled: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Device {
+    id: i64,
+    name: String,
+    room_id: Option<i64>,
+    created_at: String,
+    temperature: Option<f64>,
+    humidity: Option<f64>,
+    watthour: Option<f64>,
+    health: String,
+    health_issues: Vec<HealthIssue>,
+    online: bool,
+    mqtt_enabled: bool,
+}
+
+pub fn router(pool: SqlitePool) -> Router {
+    Router::new()
+        .route("/", get(get_devices_controller))
+        .route("/claim", post(claim_device_controller))
+        .route("/", patch(update_device_controller))
+        .route("/:id", delete(delete_device_controller))
+        .route("/:id/room", delete(unassign_device_controller))
+        .route("/:id/diagnostics", get(crate::diagnostics::get_diagnostics_controller))
+        .route("/:id/telemetry", post(crate::telemetry::telemetry_controller))
+        .layer(
+            CorsLayer::new()
+                .allow_origin("http://localhost:5173".parse::<HeaderValue>().unwrap())
+                .allow_credentials(true)
+                .allow_headers([COOKIE, CONTENT_TYPE])
+                .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE]),
+        )
+        .layer(Extension(pool))
+}
+
+/// Assigns `device_id` to `room_id`. Both have to belong to `user_id`.
+pub async fn bind_device(
+    conn: &mut SqliteConnection,
+    user_id: u32,
+    device_id: i64,
+    room_id: i64,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        "SELECT room_id FROM room WHERE room_id = ? AND owner_id = ?",
+        room_id,
+        user_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(anyhow::Error::msg("Room not found"))?;
+
+    let previous_room_id = sqlx::query_scalar!(
+        "SELECT room_id FROM device WHERE device_id = ? AND owner_id = ?",
+        device_id,
+        user_id,
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .flatten();
+
+    let res = sqlx::query!(
+        "UPDATE device SET room_id = ? WHERE device_id = ? AND owner_id = ?",
+        room_id,
+        device_id,
+        user_id,
+    )
+    .execute(&mut *conn)
+    .await?;
+    if res.rows_affected() == 0 {
+        return Err(anyhow::Error::msg("Device not found"));
+    }
+
+    if let Some(previous_room_id) = previous_room_id {
+        health::refresh_room_health(&mut *conn, previous_room_id).await?;
+    }
+    health::refresh_room_health(&mut *conn, room_id).await?;
+
+    Ok(())
+}
+
+async fn get_devices_controller(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(hub): Extension<DeviceHub>,
+    jwt_auth: JWTAuth,
+) -> Result<Json<Vec<Device>>, (StatusCode, String)> {
+    let res = get_devices_service(pool, hub, jwt_auth.id).await.map_err(|e| {
+        let err = e.to_string();
+        (StatusCode::INTERNAL_SERVER_ERROR, err)
+    })?;
+
+    Ok(Json(res))
+}
+
+async fn get_devices_service(pool: SqlitePool, hub: DeviceHub, user_id: u32) -> anyhow::Result<Vec<Device>> {
+    let rows = sqlx::query!("SELECT * FROM device WHERE owner_id = ?", user_id)
+        .fetch_all(&pool)
+        .await?;
+    let connected = hub.connected();
+
+    let mut res = vec![];
+
+    for row in rows {
+        res.push(Device {
+            id: row.device_id,
+            name: row.name,
+            room_id: row.room_id,
+            created_at: row.created_at.to_string(),
+            temperature: row.last_temperature,
+            humidity: row.last_humidity,
+            watthour: row.last_watthour,
+            health: row.health,
+            health_issues: serde_json::from_str(&row.health_issues).unwrap_or_default(),
+            online: connected.contains(&row.device_id),
+            mqtt_enabled: row.mqtt_enabled,
+        });
+    }
+
+    Ok(res)
+}
+
+/// Creates or refreshes the claim code an unclaimed device shows to its owner.
+pub async fn create_claim_code(pool: &SqlitePool, device_id: i64, ttl: Duration) -> anyhow::Result<String> {
+    sqlx::query!("DELETE FROM device_claim WHERE expires_at < datetime('now')")
+        .execute(pool)
+        .await?;
+
+    let expires_in = format!("+{} seconds", ttl.as_secs());
+    let mut attempts = 0;
+    loop {
+        let code = generate_claim_code();
+        let res = sqlx::query!(
+            r#"
+            INSERT INTO device_claim(device_id, code, expires_at) VALUES (?, ?, datetime('now', ?))
+                ON CONFLICT(device_id) DO UPDATE SET code = excluded.code, expires_at = excluded.expires_at
+            "#,
+            device_id,
+            code,
+            expires_in,
+        )
+        .execute(pool)
+        .await;
+
+        // Another device may hold the same code, just roll a new one.
+        match res {
+            Ok(_) => return Ok(code),
+            Err(_) if attempts < 5 => attempts += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+async fn claim_device_controller(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(hub): Extension<DeviceHub>,
+    jwt_auth: JWTAuth,
+    Json(claim_device_dto): Json<ClaimDeviceDto>,
+) -> Result<Json<Device>, (StatusCode, String)> {
+    let res = claim_device_service(pool, hub, jwt_auth.id, claim_device_dto)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn claim_device_service(
+    pool: SqlitePool,
+    hub: DeviceHub,
+    user_id: u32,
+    dto: ClaimDeviceDto,
+) -> anyhow::Result<Device> {
+    let code = dto.code.trim().to_uppercase();
+    let mut tx = pool.begin().await?;
+    let device_id = sqlx::query!(
+        r#"SELECT device_id as "device_id!" FROM device_claim WHERE code = ? AND expires_at > datetime('now')"#,
+        code
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(anyhow::Error::msg("Invalid or expired claim code"))?
+    .device_id;
+
+    // Credentials can only be handed out over the socket the code was shown on.
+    if !hub.is_connected(device_id) {
+        return Err(anyhow::Error::msg("Device is not connected"));
+    }
+
+    let token = generate_token();
+    let secret_hash = hash_token(&token);
+    let name = dto.name.unwrap_or_default();
+    // Devices registered before credentials existed can only be claimed again
+    // by their owner.
+    let claimed = sqlx::query!(
+        r#"
+            INSERT INTO device(device_id, owner_id, name, secret_hash) VALUES (?, ?, ?, ?)
+            ON CONFLICT(device_id) DO UPDATE SET secret_hash = excluded.secret_hash
+            WHERE device.secret_hash IS NULL AND device.owner_id = excluded.owner_id
+        "#,
+        device_id,
+        user_id,
+        name,
+        secret_hash,
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if claimed == 0 {
+        return Err(anyhow::Error::msg("Device belongs to another user"));
+    }
+    sqlx::query!("DELETE FROM device_claim WHERE device_id = ?", device_id)
+        .execute(&mut *tx)
+        .await?;
+
+    if let Some(room_id) = dto.room_id {
+        bind_device(&mut tx, user_id, device_id, room_id).await?;
+    }
+
+    let res = sqlx::query!("SELECT * FROM device WHERE device_id = ?", device_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    // Without its token the device is claimed again the next time it connects.
+    if let Err(e) = hub.send(device_id, WsOutputData::Credentials { token }).await {
+        sqlx::query!(
+            "UPDATE device SET secret_hash = NULL WHERE device_id = ? AND secret_hash = ?",
+            device_id,
+            secret_hash,
+        )
+        .execute(&pool)
+        .await?;
+        return Err(e.into());
+    }
+
+    Ok(Device {
+        id: res.device_id,
+        name: res.name,
+        room_id: res.room_id,
+        created_at: res.created_at.to_string(),
+        temperature: res.last_temperature,
+        humidity: res.last_humidity,
+        watthour: res.last_watthour,
+        health: res.health,
+        health_issues: serde_json::from_str(&res.health_issues).unwrap_or_default(),
+        online: hub.is_connected(device_id),
+        mqtt_enabled: res.mqtt_enabled,
+    })
+}
+
+async fn update_device_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Json(update_device_dto): Json<UpdateDeviceDto>,
+) -> Result<String, (StatusCode, String)> {
+    update_device_service(pool, jwt_auth.id, update_device_dto)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok("Successfully updated".to_string())
+}
+
+async fn update_device_service(
+    pool: SqlitePool,
+    user_id: u32,
+    dto: UpdateDeviceDto,
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    let res = sqlx::query!(
+        r#"
+        UPDATE device SET name = COALESCE(?, name), mqtt_enabled = COALESCE(?, mqtt_enabled)
+        WHERE owner_id = ? AND device_id = ?
+        "#,
+        dto.name,
+        dto.mqtt_enabled,
+        user_id,
+        dto.id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    if res.rows_affected() == 0 {
+        return Err(anyhow::Error::msg("Device not found"));
+    }
+
+    if let Some(room_id) = dto.room_id {
+        bind_device(&mut tx, user_id, dto.id, room_id).await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn unassign_device_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Path(id): Path<i64>,
+) -> Result<String, (StatusCode, String)> {
+    unassign_device_service(pool, jwt_auth.id, id)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok("Successfully updated".to_string())
+}
+
+async fn unassign_device_service(pool: SqlitePool, user_id: u32, device_id: i64) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    let room_id = sqlx::query_scalar!(
+        "SELECT room_id FROM device WHERE owner_id = ? AND device_id = ?",
+        user_id,
+        device_id,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .flatten();
+    sqlx::query!(
+        "UPDATE device SET room_id = NULL WHERE owner_id = ? AND device_id = ?",
+        user_id,
+        device_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if let Some(room_id) = room_id {
+        health::refresh_room_health(&mut tx, room_id).await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn delete_device_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Path(id): Path<i64>,
+) -> Result<String, (StatusCode, String)> {
+    delete_device_service(pool, jwt_auth.id, id)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok("Successfully deleted".to_string())
+}
+
+async fn delete_device_service(pool: SqlitePool, user_id: u32, device_id: i64) -> anyhow::Result<()> {
+    sqlx::query!(
+        "DELETE FROM device WHERE owner_id = ? AND device_id = ?",
+        user_id,
+        device_id,
+    )
+    .execute(&pool)
+    .await?;
+
+    Ok(())
+}
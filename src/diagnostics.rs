@@ -0,0 +1,245 @@
+use crate::hub::DeviceHub;
+use crate::ingest::now_ms;
+use crate::utils::config::env_or;
+use crate::utils::jwt::JWTAuth;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+
+/// Health report a device sends periodically.
+#[derive(Debug, Deserialize)]
+pub struct DiagnosticsReport {
+    pub rssi: Option<i64>,
+    pub uptime_ms: Option<i64>,
+    pub free_heap: Option<i64>,
+    pub battery_voltage: Option<f64>,
+    pub reset_reason: Option<String>,
+    /// How far the device's clock ran ahead of the last `Time` message when
+    /// the next one arrived, negative if it fell behind.
+    pub clock_drift_ms: Option<i64>,
+}
+
+/// Firmware log line.
+#[derive(Debug, Deserialize)]
+pub struct LogLine {
+    pub level: String,
+    pub message: String,
+    /// Unix epoch in milliseconds, defaults to the time of arrival.
+    pub ts: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Diagnostics {
+    rssi: Option<i64>,
+    uptime_ms: Option<i64>,
+    free_heap: Option<i64>,
+    battery_voltage: Option<f64>,
+    reset_reason: Option<String>,
+    clock_drift_ms: Option<i64>,
+    created_at: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Log {
+    level: String,
+    message: String,
+    created_at: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeviceDiagnostics {
+    device_id: i64,
+    online: bool,
+    protocol_version: Option<i64>,
+    firmware_version: Option<String>,
+    hardware_model: Option<String>,
+    capabilities: Vec<String>,
+    last_hello_at: Option<String>,
+    malformed_frames: i64,
+    seq_gaps: i64,
+    seq_duplicates: i64,
+    /// Latest drift reported by the device.
+    clock_drift_ms: Option<i64>,
+    reports: Vec<Diagnostics>,
+    logs: Vec<Log>,
+}
+
+pub async fn record_diagnostics(
+    conn: &mut SqliteConnection,
+    device_id: i64,
+    report: DiagnosticsReport,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO device_diagnostics(device_id, rssi, uptime_ms, free_heap, battery_voltage, reset_reason, clock_drift_ms, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, datetime('now'))
+        "#,
+        device_id,
+        report.rssi,
+        report.uptime_ms,
+        report.free_heap,
+        report.battery_voltage,
+        report.reset_reason,
+        report.clock_drift_ms,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let retention = env_or("DIAGNOSTICS_RETENTION", 500i64);
+    sqlx::query!(
+        r#"
+        DELETE FROM device_diagnostics
+            WHERE device_id = ? AND diagnostics_id NOT IN (
+                SELECT diagnostics_id FROM device_diagnostics
+                    WHERE device_id = ?
+                    ORDER BY diagnostics_id DESC
+                    LIMIT ?
+            )
+        "#,
+        device_id,
+        device_id,
+        retention,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn record_log(conn: &mut SqliteConnection, device_id: i64, line: LogLine) -> anyhow::Result<()> {
+    let ts = line.ts.unwrap_or_else(now_ms) / 1000;
+    sqlx::query!(
+        "INSERT INTO device_log(device_id, level, message, created_at) VALUES (?, ?, ?, datetime(?, 'unixepoch'))",
+        device_id,
+        line.level,
+        line.message,
+        ts,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let retention = env_or("LOG_RETENTION", 1000i64);
+    sqlx::query!(
+        r#"
+        DELETE FROM device_log
+            WHERE device_id = ? AND log_id NOT IN (
+                SELECT log_id FROM device_log
+                    WHERE device_id = ?
+                    ORDER BY log_id DESC
+                    LIMIT ?
+            )
+        "#,
+        device_id,
+        device_id,
+        retention,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Counts a frame that could not be decoded or did not belong to the device.
+pub async fn record_malformed_frame(pool: &SqlitePool, device_id: i64) -> anyhow::Result<()> {
+    sqlx::query!(
+        "UPDATE device_info SET malformed_frames = malformed_frames + 1 WHERE device_id = ?",
+        device_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_diagnostics_controller(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(hub): Extension<DeviceHub>,
+    jwt_auth: JWTAuth,
+    Path(id): Path<i64>,
+) -> Result<Json<DeviceDiagnostics>, (StatusCode, String)> {
+    let res = get_diagnostics_service(pool, hub, jwt_auth.id, id)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn get_diagnostics_service(
+    pool: SqlitePool,
+    hub: DeviceHub,
+    user_id: u32,
+    device_id: i64,
+) -> anyhow::Result<DeviceDiagnostics> {
+    let info = sqlx::query!(
+        r#"
+        SELECT d.device_id, i.protocol_version, i.firmware_version, i.hardware_model, i.capabilities,
+            i.last_hello_at, i.malformed_frames, i.seq_gaps, i.seq_duplicates
+            FROM device d
+            LEFT JOIN device_info i ON i.device_id = d.device_id
+            WHERE d.device_id = ? AND d.owner_id = ?
+        "#,
+        device_id,
+        user_id,
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or(anyhow::Error::msg("Device not found"))?;
+
+    let reports = sqlx::query!(
+        "SELECT * FROM device_diagnostics WHERE device_id = ? ORDER BY diagnostics_id DESC LIMIT 50",
+        device_id
+    )
+    .fetch_all(&pool)
+    .await?;
+    let logs = sqlx::query!(
+        "SELECT * FROM device_log WHERE device_id = ? ORDER BY log_id DESC LIMIT 100",
+        device_id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let capabilities = info
+        .capabilities
+        .and_then(|c| serde_json::from_str(&c).ok())
+        .unwrap_or_default();
+    let online = hub.is_connected(device_id);
+
+    Ok(DeviceDiagnostics {
+        device_id,
+        online,
+        protocol_version: info.protocol_version,
+        firmware_version: info.firmware_version,
+        hardware_model: info.hardware_model,
+        capabilities,
+        last_hello_at: info.last_hello_at.map(|d| d.to_string()),
+        malformed_frames: info.malformed_frames.unwrap_or_default(),
+        seq_gaps: info.seq_gaps.unwrap_or_default(),
+        seq_duplicates: info.seq_duplicates.unwrap_or_default(),
+        clock_drift_ms: reports.iter().find_map(|r| r.clock_drift_ms),
+        reports: reports
+            .into_iter()
+            .map(|r| Diagnostics {
+                rssi: r.rssi,
+                uptime_ms: r.uptime_ms,
+                free_heap: r.free_heap,
+                battery_voltage: r.battery_voltage,
+                reset_reason: r.reset_reason,
+                clock_drift_ms: r.clock_drift_ms,
+                created_at: r.created_at.to_string(),
+            })
+            .collect(),
+        logs: logs
+            .into_iter()
+            .map(|l| Log {
+                level: l.level,
+                message: l.message,
+                created_at: l.created_at.to_string(),
+            })
+            .collect(),
+    })
+}
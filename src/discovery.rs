@@ -0,0 +1,41 @@
+use crate::esp_websockets::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::utils::config::env_or;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+const SERVICE_TYPE: &str = "_smarty._tcp.local.";
+
+/// Advertises the backend on the LAN as a `_smarty._tcp` DNS-SD service, so
+/// devices can find it without a compiled-in address. Enabled with
+/// `MDNS_ENABLED`; the returned daemon must be kept alive for as long as the
+/// service should be announced.
+pub fn advertise(port: u16) -> anyhow::Result<Option<ServiceDaemon>> {
+    if !env_or("MDNS_ENABLED", false) {
+        return Ok(None);
+    }
+
+    let instance = env_or("MDNS_INSTANCE", "smarty".to_string());
+    let host_name = env_or("MDNS_HOSTNAME", format!("{}.local.", instance));
+    let protocol_version = PROTOCOL_VERSION.to_string();
+    let min_protocol_version = MIN_PROTOCOL_VERSION.to_string();
+    let properties = [
+        ("path", "/ws/{device_id}"),
+        ("protocol_version", protocol_version.as_str()),
+        ("min_protocol_version", min_protocol_version.as_str()),
+    ];
+
+    let daemon = ServiceDaemon::new()?;
+    // Addresses are filled in and kept up to date by the daemon.
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &instance,
+        &host_name,
+        "",
+        port,
+        &properties[..],
+    )?
+    .enable_addr_auto();
+    daemon.register(service)?;
+
+    println!("Advertising {}.{} on port {}", instance, SERVICE_TYPE, port);
+    Ok(Some(daemon))
+}
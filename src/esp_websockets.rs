@@ -4,18 +4,34 @@ use axum::{
         ws::{Message, WebSocket},
         Path, WebSocketUpgrade,
     },
-    http::Response,
+    http::{Response, StatusCode},
+    response::IntoResponse,
     Extension,
 };
+use crate::actuator::{self, StateReport};
+use crate::clock;
+use crate::codec::Codec;
+use crate::device;
+use crate::diagnostics::{self, DiagnosticsReport, LogLine};
+use crate::firmware::{self, UpdateStatus};
+use crate::event_log::EventReport;
+use crate::events::{self, DeviceEvent};
+use crate::hub::DeviceHub;
+use crate::ingest::{self, InvalidReading, MetricReading, TempReading};
+use crate::utils::config::env_or;
+use crate::utils::device_token::{verify_token, DeviceToken};
 use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{mpsc::UnboundedSender, Mutex};
+use std::{collections::HashSet, time::Duration};
+use tokio::time::{Instant, MissedTickBehavior};
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct WsInputData {
     pub device_id: i64,
+    /// Monotonically increasing per device boot, used to drop replayed messages.
+    #[serde(default)]
+    pub seq: Option<i64>,
 
     #[serde(flatten)]
     pub inner: WsInnerData,
@@ -25,110 +41,423 @@ pub struct WsInputData {
 #[serde(tag = "type", content = "data")]
 #[serde(rename_all = "snake_case")]
 pub enum WsInnerData {
+    Hello(Hello),
     Move,
+    Heartbeat,
     Temp { temp: f64, hum: f64, wh: f64 },
+    TempBatch { readings: Vec<TempReading> },
+    Metric(MetricReading),
+    Diagnostics(DiagnosticsReport),
+    Log(LogLine),
+    UpdateStatus(UpdateStatus),
+    State(StateReport),
+    Event(EventReport),
+    /// Asks for a `Time` message, e.g. after the device lost track of time.
+    TimeRequest,
 }
 
-#[derive(Debug, Serialize)]
+/// First message a device has to send after connecting.
+#[derive(Debug, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub firmware_version: String,
+    pub hardware_model: String,
+    /// Sensors, actuators and optional commands present on the device.
+    #[serde(default)]
+    pub capabilities: HashSet<String>,
+    /// Changes whenever the device reboots, which restarts its sequence numbers.
+    /// Without one, every hello restarts them.
+    #[serde(default)]
+    pub boot_id: Option<String>,
+    /// Codec to switch to once the server has answered with `Welcome`.
+    #[serde(default)]
+    pub codec: Option<Codec>,
+}
+
+#[derive(Debug, Clone, Serialize)]
 #[serde(tag = "type", content = "data")]
 #[serde(rename_all = "snake_case")]
 pub enum WsOutputData {
+    Welcome { protocol_version: u32, codec: Codec },
+    Rejected { reason: String },
     Settings { presence_timeout: u64 },
+    /// Sent to unclaimed devices, to be shown on their display or console.
+    ClaimCode { code: String, expires_in_ms: u64 },
+    /// Issued once the device has been claimed. It must be presented on every
+    /// following connection.
+    Credentials { token: String },
+    /// Asks the device to download and install a firmware image.
+    Update { version: String, url: String, sha256: String, size: u64 },
+    /// Wall clock for devices without an RTC. Sent on connect, periodically
+    /// and on request.
+    Time { epoch_ms: i64, utc_offset_minutes: i32 },
+    /// Switches an output, acknowledged by the device with a `State` report.
+    SetState { channel: u32, value: f64 },
 }
 
-lazy_static::lazy_static! {
-    pub static ref CLIENTS: Arc<Mutex<HashMap<i64, UnboundedSender<WsOutputData>>>> = Arc::new(Mutex::new(HashMap::new()));
+impl WsOutputData {
+    /// Capability the device must advertise in its hello to receive this message.
+    /// `None` means every device understands it.
+    pub fn required_capability(&self) -> Option<&'static str> {
+        match self {
+            WsOutputData::Welcome { .. }
+            | WsOutputData::Rejected { .. }
+            | WsOutputData::Settings { .. }
+            | WsOutputData::ClaimCode { .. }
+            | WsOutputData::Credentials { .. }
+            | WsOutputData::Time { .. } => None,
+            WsOutputData::Update { .. } => Some("ota"),
+            // Advertised by devices with switchable outputs of any kind.
+            WsOutputData::SetState { .. } => Some("relay"),
+        }
+    }
 }
 
+/// Newest protocol version spoken by the server.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version the server still accepts.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
     Path(device_id): Path<i64>,
+    DeviceToken(token): DeviceToken,
     Extension(pool): Extension<SqlitePool>,
+    Extension(hub): Extension<DeviceHub>,
 ) -> Response<Body> {
-    return ws.on_upgrade(move |socket| async move {
-        handle_socket(socket, device_id, pool).await;
+    let device = sqlx::query!("SELECT secret_hash FROM device WHERE device_id = ?", device_id)
+        .fetch_optional(&pool)
+        .await;
+    let ws = ws.protocols(Codec::SUBPROTOCOLS);
+
+    match device {
+        // Registered before credentials existed, has to be claimed again.
+        Ok(Some(device)) if device.secret_hash.is_none() => ws.on_upgrade(move |socket| async move {
+            handle_unclaimed(socket, device_id, pool, hub).await;
+        }),
+        Ok(Some(device)) => {
+            if !verify_token(token.as_deref(), device.secret_hash.as_deref()) {
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
+
+            ws.on_upgrade(move |socket| async move {
+                handle_socket(socket, device_id, pool, hub).await;
+            })
+        }
+        Ok(None) => ws.on_upgrade(move |socket| async move {
+            handle_unclaimed(socket, device_id, pool, hub).await;
+        }),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+fn negotiated_codec(socket: &WebSocket) -> Codec {
+    socket
+        .protocol()
+        .and_then(|p| p.to_str().ok())
+        .and_then(Codec::from_subprotocol)
+        .unwrap_or_default()
+}
+
+/// Keeps an unknown device connected while its owner types the claim code
+/// into the app, then hands it its credentials and closes the connection.
+async fn handle_unclaimed(mut socket: WebSocket, device_id: i64, pool: SqlitePool, hub: DeviceHub) {
+    let codec = negotiated_codec(&socket);
+    let mut connection = match hub.register(device_id) {
+        Ok(connection) => connection,
+        Err(e) => {
+            reject(&mut socket, codec, &e.to_string()).await;
+            return;
+        }
+    };
+    let ttl = Duration::from_millis(env_or("CLAIM_CODE_TTL_MS", 600000));
+    let code = match device::create_claim_code(&pool, device_id, ttl).await {
+        Ok(code) => code,
+        Err(e) => {
+            println!("Failed to create claim code for client {}: {}", device_id, e);
+            reject(&mut socket, codec, "Failed to create claim code").await;
+            hub.unregister(device_id, connection.generation);
+            return;
+        }
+    };
+    connection.queue(WsOutputData::ClaimCode {
+        code,
+        expires_in_ms: ttl.as_millis() as u64,
     });
+
+    let expiry = tokio::time::sleep(ttl);
+    tokio::pin!(expiry);
+    loop {
+        tokio::select! {
+            msg = socket.recv() => {
+                match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => {}
+                }
+            }
+            msg = connection.recv() => {
+                let Some(msg) = msg else {
+                    reject(&mut socket, codec, "Replaced by a newer connection").await;
+                    break;
+                };
+                let claimed = matches!(msg, WsOutputData::Credentials { .. });
+                if send(&mut socket, codec, &msg).await.is_err() {
+                    break;
+                }
+
+                // The device reconnects with its new credentials.
+                if claimed {
+                    _ = socket.send(Message::Close(None)).await;
+                    break;
+                }
+            }
+            _ = &mut expiry => {
+                reject(&mut socket, codec, "Claim code expired").await;
+                break;
+            }
+        }
+    }
+
+    hub.unregister(device_id, connection.generation);
 }
 
-async fn handle_socket(mut socket: WebSocket, device_id: i64, pool: SqlitePool) {
-    let room = sqlx::query!("SELECT * FROM room WHERE room_id = ?", device_id)
-        .fetch_optional(&pool)
-        .await;
-    if room.is_err() || room.expect("Room is none").is_none() {
+async fn handle_socket(mut socket: WebSocket, device_id: i64, pool: SqlitePool, hub: DeviceHub) {
+    let mut codec = negotiated_codec(&socket);
+
+    let hello_timeout = Duration::from_millis(env_or("WS_HELLO_TIMEOUT_MS", 10000));
+    let hello = match tokio::time::timeout(hello_timeout, receive_hello(&mut socket, device_id, codec)).await {
+        Ok(Some(hello)) => hello,
+        _ => {
+            reject(&mut socket, codec, "Expected hello message").await;
+            return;
+        }
+    };
+    if hello.protocol_version < MIN_PROTOCOL_VERSION {
+        let reason = format!(
+            "Protocol version {} is not supported, minimum is {}",
+            hello.protocol_version, MIN_PROTOCOL_VERSION
+        );
+        reject(&mut socket, codec, &reason).await;
         return;
     }
 
-    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-    {
-        _ = tx.send(WsOutputData::Settings {
-            presence_timeout: 60000,
-        });
+    let mut connection = match hub.register(device_id) {
+        Ok(connection) => connection,
+        Err(e) => {
+            reject(&mut socket, codec, &e.to_string()).await;
+            return;
+        }
+    };
+
+    // Devices newer than the server are downgraded to our newest version.
+    let protocol_version = hello.protocol_version.min(PROTOCOL_VERSION);
+    if let Err(e) = save_hello(&pool, device_id, protocol_version, &hello).await {
+        println!("Failed to store hello of client {}: {}", device_id, e);
+    }
+    let capabilities = hello.capabilities;
+
+    // The welcome still goes out in the initial codec, everything after it in
+    // the one requested by the hello.
+    let new_codec = hello.codec.unwrap_or(codec);
+    let welcome = WsOutputData::Welcome {
+        protocol_version,
+        codec: new_codec,
+    };
+    if send(&mut socket, codec, &welcome).await.is_err() {
+        hub.unregister(device_id, connection.generation);
+        return;
+    }
+    codec = new_codec;
 
-        CLIENTS.lock().await.insert(device_id, tx);
+    connection.queue(settings());
+    connection.queue(clock::time_message(&pool, device_id).await);
+    for command in actuator::pending_commands(&pool, device_id).await.unwrap_or_default() {
+        connection.queue(command);
     }
+    if let Ok(Some(update)) = firmware::on_hello(&pool, device_id, &hello.firmware_version).await {
+        connection.queue(update);
+    }
+    // Paired with the `Offline` sent when the last connection goes away.
+    if connection.first {
+        events::emit(DeviceEvent::Online { device_id });
+    }
+
+    let ping_interval = Duration::from_millis(env_or("WS_PING_INTERVAL_MS", 15000u64).max(1));
+    let ping_timeout = Duration::from_millis(env_or("WS_PING_TIMEOUT_MS", 45000));
+    let mut ping_ticker = tokio::time::interval(ping_interval);
+    ping_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_seen = Instant::now();
+    let time_sync_interval = Duration::from_millis(env_or("WS_TIME_SYNC_INTERVAL_MS", 3600000u64).max(1));
+    let mut time_ticker = tokio::time::interval_at(Instant::now() + time_sync_interval, time_sync_interval);
+    time_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
     loop {
         tokio::select! {
-            Some(msg) = socket.recv() => {
-                let msg = if let Ok(msg) = msg {
-                    let data = msg.clone().into_data();
-                    let data: WsInputData = if let Ok(data) = serde_json::from_slice(&data) {
-                        data
-                    } else {
+            msg = socket.recv() => {
+                let msg = match msg {
+                    Some(Ok(msg)) => msg,
+                    _ => {
+                        println!("Client {} disconnected", device_id);
+                        break;
+                    }
+                };
+
+                // Any frame from the device proves the connection is alive.
+                last_seen = Instant::now();
+                match msg {
+                    Message::Ping(_) | Message::Pong(_) => continue,
+                    Message::Close(_) => {
+                        println!("Client {} disconnected", device_id);
+                        break;
+                    }
+                    _ => {}
+                }
+
+                let data = msg.clone().into_data();
+                let data = match codec.decode::<WsInputData>(&data) {
+                    Ok(data) if data.device_id == device_id => data,
+                    _ => {
+                        _ = diagnostics::record_malformed_frame(&pool, device_id).await;
                         continue;
-                    };
-
-                    if let WsInnerData::Temp { temp, hum, wh } = data.inner {
-                        _ = sqlx::query!(
-                            "INSERT INTO room_history (room_id, temperature, humidity, watthour, created_at)
-                            VALUES (?, ?, ?, ?, datetime('now'))",
-                            device_id,
-                            temp,
-                            hum,
-                            wh,
-                        )
-                            .execute(&pool).await;
-
-                        _ = sqlx::query!(
-                            "UPDATE room SET current_temperature = ?, current_humidity = ?, current_watthour = ? WHERE room_id = ?",
-                            temp,
-                            hum,
-                            wh,
-                            device_id,
-                        )
-                            .execute(&pool).await;
-                    } else if let WsInnerData::Move = data.inner {
-                        _ = sqlx::query!(
-                            "UPDATE room SET last_presence = (strftime('%s', 'now')) WHERE room_id = ?",
-                            device_id,
-                        )
-                            .execute(&pool).await;
                     }
+                };
 
-                    msg
-                } else {
-                    break;
+                let time_request = matches!(data.inner, WsInnerData::TimeRequest);
+                // Invalid readings are acknowledged too, sending them again
+                // does not help.
+                let acknowledged = match ingest::process_input(&pool, &hub, device_id, data).await {
+                    Ok(acknowledged) => acknowledged,
+                    Err(e) => {
+                        println!("Failed to process message of client {}: {}", device_id, e);
+                        e.is::<InvalidReading>()
+                    }
                 };
+                if time_request {
+                    let time = clock::time_message(&pool, device_id).await;
+                    if send(&mut socket, codec, &time).await.is_err() {
+                        break;
+                    }
+                }
+                if !acknowledged {
+                    continue;
+                }
 
                 if socket.send(msg).await.is_err() {
                     break;
                 }
             }
-            Some(msg) = rx.recv() => {
-                let msg = serde_json::to_string(&msg).unwrap();
-                let msg = Message::from(msg);
-                if socket.send(msg).await.is_err() {
+            msg = connection.recv() => {
+                let Some(msg) = msg else {
+                    println!("Client {} replaced by a newer connection", device_id);
+                    reject(&mut socket, codec, "Replaced by a newer connection").await;
+                    break;
+                };
+                if let Some(capability) = msg.required_capability() {
+                    if !capabilities.contains(capability) {
+                        continue;
+                    }
+                }
+
+                if send(&mut socket, codec, &msg).await.is_err() {
                     break;
                 }
             }
-            else => {
-                println!("Client {} disconnected", device_id);
-                break;
+            _ = ping_ticker.tick() => {
+                if last_seen.elapsed() > ping_timeout {
+                    println!("Client {} timed out", device_id);
+                    break;
+                }
+                // Devices may only answer pings, which never reach the ingest.
+                _ = ingest::mark_seen(&pool, device_id).await;
+
+                if socket.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            }
+            _ = time_ticker.tick() => {
+                let time = clock::time_message(&pool, device_id).await;
+                if send(&mut socket, codec, &time).await.is_err() {
+                    break;
+                }
             }
         }
     }
 
-    {
-        CLIENTS.lock().await.remove(&device_id);
+    // The device only goes offline with its last connection.
+    if hub.unregister(device_id, connection.generation) {
+        events::emit(DeviceEvent::Offline { device_id });
     }
 }
+
+/// Waits for the device's hello, skipping control frames. Returns `None` if the
+/// socket closes or the first data frame is anything else.
+async fn receive_hello(socket: &mut WebSocket, device_id: i64, codec: Codec) -> Option<Hello> {
+    loop {
+        let msg = socket.recv().await?.ok()?;
+        match msg {
+            Message::Ping(_) | Message::Pong(_) => continue,
+            Message::Close(_) => return None,
+            _ => {}
+        }
+
+        let data: WsInputData = codec.decode(&msg.into_data()).ok()?;
+        return match data.inner {
+            WsInnerData::Hello(hello) if data.device_id == device_id => Some(hello),
+            _ => None,
+        };
+    }
+}
+
+async fn send(socket: &mut WebSocket, codec: Codec, msg: &WsOutputData) -> anyhow::Result<()> {
+    let msg = codec.encode_message(msg)?;
+    socket.send(msg).await?;
+    Ok(())
+}
+
+async fn reject(socket: &mut WebSocket, codec: Codec, reason: &str) {
+    let msg = WsOutputData::Rejected {
+        reason: reason.to_string(),
+    };
+    _ = send(socket, codec, &msg).await;
+    _ = socket.send(Message::Close(None)).await;
+}
+
+/// Current settings for a device, sent on every connection.
+pub fn settings() -> WsOutputData {
+    WsOutputData::Settings {
+        presence_timeout: 60000,
+    }
+}
+
+pub async fn save_hello(
+    pool: &SqlitePool,
+    device_id: i64,
+    protocol_version: u32,
+    hello: &Hello,
+) -> anyhow::Result<()> {
+    let capabilities = serde_json::to_string(&hello.capabilities)?;
+    sqlx::query!(
+        r#"
+        INSERT INTO device_info(device_id, protocol_version, firmware_version, hardware_model, capabilities, boot_id, last_hello_at)
+        VALUES (?, ?, ?, ?, ?, ?, datetime('now'))
+        ON CONFLICT(device_id) DO UPDATE SET
+            last_seq = CASE WHEN device_info.boot_id = excluded.boot_id THEN device_info.last_seq END,
+            boot_id = excluded.boot_id,
+            protocol_version = excluded.protocol_version,
+            firmware_version = excluded.firmware_version,
+            hardware_model = excluded.hardware_model,
+            capabilities = excluded.capabilities,
+            last_hello_at = excluded.last_hello_at
+        "#,
+        device_id,
+        protocol_version,
+        hello.firmware_version,
+        hello.hardware_model,
+        capabilities,
+        hello.boot_id,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,224 @@
+use crate::automation;
+use crate::events::{self, DeviceEvent};
+use crate::heating;
+use crate::hub::DeviceHub;
+use crate::ingest::{now_ms, InvalidReading};
+use crate::utils::config::env_or;
+use crate::utils::jwt::JWTAuth;
+use crate::utils::Pagination;
+use axum::extract::Query;
+use axum::http::{HeaderValue, StatusCode};
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use http::header::{CONTENT_TYPE, COOKIE};
+use http::Method;
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+use tower_http::cors::CorsLayer;
+
+/// Door or window reed switch, reporting 1 when open and 0 when closed.
+pub const CONTACT: &str = "contact";
+
+/// Something that happened on the device, e.g. a button press (`button`, the
+/// number of presses), a contact opening (`contact`) or an alarm going off
+/// (`smoke`, `leak`, 1 while active).
+#[derive(Debug, Deserialize)]
+pub struct EventReport {
+    pub kind: String,
+    pub value: f64,
+    /// Unix epoch in milliseconds, defaults to the time of arrival.
+    pub ts: Option<i64>,
+}
+
+/// Event as stored, for `react` to act on once it is committed.
+pub struct RecordedEvent {
+    room_id: Option<i64>,
+    kind: String,
+    value: f64,
+    /// Events older than `EVENT_MAX_AGE_MS` are only logged.
+    recent: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct EventFilter {
+    room_id: Option<i64>,
+    device_id: Option<i64>,
+    kind: Option<String>,
+    /// Unix epoch in milliseconds, inclusive.
+    from: Option<i64>,
+    /// Unix epoch in milliseconds, exclusive.
+    to: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Event {
+    id: i64,
+    device_id: i64,
+    room_id: Option<i64>,
+    kind: String,
+    value: f64,
+    created_at: String,
+}
+
+pub fn router(pool: SqlitePool) -> Router {
+    Router::new()
+        .route("/", get(get_events_controller))
+        .layer(
+            CorsLayer::new()
+                .allow_origin("http://localhost:5173".parse::<HeaderValue>().unwrap())
+                .allow_credentials(true)
+                .allow_headers([COOKIE, CONTENT_TYPE])
+                .allow_methods([Method::GET]),
+        )
+        .layer(Extension(pool))
+}
+
+/// Logs the event in the room the device is assigned to. Timestamps too far
+/// in the future are refused like readings.
+pub async fn record_event(
+    conn: &mut SqliteConnection,
+    device_id: i64,
+    report: EventReport,
+) -> anyhow::Result<RecordedEvent> {
+    let room_id = sqlx::query_scalar!("SELECT room_id FROM device WHERE device_id = ?", device_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    let now = now_ms();
+    let ts = report.ts.unwrap_or(now);
+    if ts <= 0 || ts > now + env_or("TELEMETRY_MAX_FUTURE_MS", 60000i64) {
+        return Err(InvalidReading("Invalid timestamp".to_string()).into());
+    }
+    let recent = now - ts <= env_or("EVENT_MAX_AGE_MS", 60000i64);
+
+    let created_at = ts / 1000;
+    sqlx::query!(
+        r#"
+        INSERT INTO device_event(device_id, room_id, kind, value, created_at)
+        VALUES (?, ?, ?, ?, datetime(?, 'unixepoch'))
+        "#,
+        device_id,
+        room_id,
+        report.kind,
+        report.value,
+        created_at,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let retention = env_or("EVENT_RETENTION", 1000i64);
+    sqlx::query!(
+        r#"
+        DELETE FROM device_event
+            WHERE device_id = ? AND event_id NOT IN (
+                SELECT event_id FROM device_event
+                    WHERE device_id = ?
+                    ORDER BY event_id DESC
+                    LIMIT ?
+            )
+        "#,
+        device_id,
+        device_id,
+        retention,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(RecordedEvent {
+        room_id,
+        kind: report.kind,
+        value: report.value,
+        recent,
+    })
+}
+
+/// Publishes a stored event, then lets contacts pause heating and runs the
+/// automations it triggers. Events older than `EVENT_MAX_AGE_MS`, e.g.
+/// buffered while offline, have no effect.
+pub async fn react(pool: &SqlitePool, hub: &DeviceHub, device_id: i64, event: RecordedEvent) -> anyhow::Result<()> {
+    events::emit(DeviceEvent::Event {
+        device_id,
+        room_id: event.room_id,
+        kind: event.kind.clone(),
+        value: event.value,
+    });
+
+    if !event.recent {
+        return Ok(());
+    }
+    if let (CONTACT, Some(room_id)) = (event.kind.as_str(), event.room_id) {
+        heating::on_contact(pool, hub, room_id, event.value > 0.0).await?;
+    }
+    automation::trigger(pool, hub, device_id, event.room_id, &event.kind, event.value).await
+}
+
+async fn get_events_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Query(filter): Query<EventFilter>,
+    Query(pagination): Query<Pagination>,
+) -> Result<Json<Vec<Event>>, (StatusCode, String)> {
+    let res = get_events_service(pool, jwt_auth.id, filter, pagination)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn get_events_service(
+    pool: SqlitePool,
+    user_id: u32,
+    filter: EventFilter,
+    pagination: Pagination,
+) -> anyhow::Result<Vec<Event>> {
+    let from = filter.from.map(|ms| ms / 1000);
+    let to = filter.to.map(|ms| ms / 1000);
+    let rows = sqlx::query!(
+        r#"
+        SELECT e.event_id AS "event_id!", e.device_id, e.room_id, e.kind, e.value, e.created_at
+            FROM device_event e
+            INNER JOIN device d ON d.device_id = e.device_id
+            WHERE d.owner_id = ?
+            AND (? IS NULL OR e.room_id = ?)
+            AND (? IS NULL OR e.device_id = ?)
+            AND (? IS NULL OR e.kind = ?)
+            AND (? IS NULL OR e.created_at >= datetime(?, 'unixepoch'))
+            AND (? IS NULL OR e.created_at < datetime(?, 'unixepoch'))
+            ORDER BY e.created_at DESC, e.event_id DESC
+            LIMIT COALESCE(?, -1)
+            OFFSET COALESCE(?, 0)
+        "#,
+        user_id,
+        filter.room_id,
+        filter.room_id,
+        filter.device_id,
+        filter.device_id,
+        filter.kind,
+        filter.kind,
+        from,
+        from,
+        to,
+        to,
+        pagination.take,
+        pagination.skip,
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let mut res = vec![];
+
+    for row in rows {
+        res.push(Event {
+            id: row.event_id,
+            device_id: row.device_id,
+            room_id: row.room_id,
+            kind: row.kind,
+            value: row.value,
+            created_at: row.created_at.to_string(),
+        });
+    }
+
+    Ok(res)
+}
@@ -0,0 +1,83 @@
+use crate::health::HealthIssue;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data")]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceEvent {
+    Online { device_id: i64 },
+    Offline { device_id: i64 },
+    Alert {
+        device_id: i64,
+        room_id: Option<i64>,
+        issue: HealthIssue,
+    },
+    /// Reported by a device, e.g. a button press or a contact opening.
+    Event {
+        device_id: i64,
+        room_id: Option<i64>,
+        kind: String,
+        value: f64,
+    },
+    /// An actuator ran long enough to need servicing.
+    MaintenanceDue {
+        device_id: i64,
+        room_id: Option<i64>,
+        actuator_id: i64,
+        maintenance_id: i64,
+        name: String,
+    },
+    /// New room values after a device reported telemetry.
+    Telemetry {
+        device_id: i64,
+        room_id: i64,
+        temperature: f64,
+        humidity: f64,
+        watthour: f64,
+    },
+    Presence {
+        device_id: i64,
+        room_id: i64,
+        /// Unix epoch in seconds.
+        lastpresence: i64,
+    },
+    /// An actuator was commanded or reported its state.
+    ActuatorState {
+        actuator_id: i64,
+        device_id: i64,
+        room_id: Option<i64>,
+        state: Option<f64>,
+        desired_state: Option<f64>,
+    },
+    /// A sharp temperature drop suggests a window of the room is open.
+    WindowOpen { device_id: i64, room_id: i64 },
+    WindowClosed { room_id: i64 },
+}
+
+impl DeviceEvent {
+    /// Room the event happened in, `None` for online status which only knows
+    /// the device.
+    pub fn room_id(&self) -> Option<i64> {
+        match self {
+            DeviceEvent::Online { .. } | DeviceEvent::Offline { .. } => None,
+            DeviceEvent::Alert { room_id, .. }
+            | DeviceEvent::Event { room_id, .. }
+            | DeviceEvent::MaintenanceDue { room_id, .. }
+            | DeviceEvent::ActuatorState { room_id, .. } => *room_id,
+            DeviceEvent::Telemetry { room_id, .. }
+            | DeviceEvent::Presence { room_id, .. }
+            | DeviceEvent::WindowOpen { room_id, .. }
+            | DeviceEvent::WindowClosed { room_id } => Some(*room_id),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    pub static ref EVENTS: broadcast::Sender<DeviceEvent> = broadcast::channel(256).0;
+}
+
+/// Publishes an event to every subscriber. Having no subscribers is not an error.
+pub fn emit(event: DeviceEvent) {
+    _ = EVENTS.send(event);
+}
@@ -0,0 +1,614 @@
+use crate::esp_websockets::WsOutputData;
+use crate::hub::DeviceHub;
+use crate::telemetry;
+use crate::utils::config::env_or;
+use crate::utils::device_token::{verify_token, DeviceToken};
+use crate::utils::jwt::JWTAuth;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, Query};
+use axum::http::{HeaderValue, StatusCode};
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use http::header::{CONTENT_TYPE, COOKIE};
+use http::Method;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{SqliteConnection, SqlitePool};
+use tower_http::cors::CorsLayer;
+
+/// Progress report sent by a device while it applies an update.
+#[derive(Debug, Deserialize)]
+pub struct UpdateStatus {
+    pub version: String,
+    pub status: UpdateState,
+    pub progress: Option<u8>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateState {
+    Downloading,
+    Installing,
+    Success,
+    Failed,
+}
+
+impl UpdateState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UpdateState::Downloading => "downloading",
+            UpdateState::Installing => "installing",
+            UpdateState::Success => "success",
+            UpdateState::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct UploadFirmwareQuery {
+    version: String,
+    hardware_model: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct RolloutDto {
+    firmware_id: i64,
+    device_ids: Option<Vec<i64>>,
+    room_id: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct RollbackDto {
+    device_ids: Option<Vec<i64>>,
+    room_id: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct RolloutFilter {
+    firmware_id: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct DownloadQuery {
+    device_id: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Firmware {
+    id: i64,
+    version: String,
+    hardware_model: String,
+    sha256: String,
+    size: i64,
+    created_at: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct DeviceUpdate {
+    device_id: i64,
+    firmware_id: i64,
+    version: String,
+    previous_firmware_id: Option<i64>,
+    status: String,
+    progress: i64,
+    error: Option<String>,
+    updated_at: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct RolloutResult {
+    scheduled: Vec<i64>,
+    skipped: Vec<i64>,
+}
+
+pub fn router(pool: SqlitePool) -> Router {
+    let max_size = env_or("FIRMWARE_MAX_SIZE", 16 * 1024 * 1024);
+
+    Router::new()
+        .route("/", get(get_firmware_controller))
+        .route(
+            "/",
+            post(upload_firmware_controller).layer(DefaultBodyLimit::max(max_size)),
+        )
+        .route("/rollout", get(get_rollout_controller))
+        .route("/rollout", post(rollout_controller))
+        .route("/rollback", post(rollback_controller))
+        .route("/:id/download", get(download_firmware_controller))
+        .layer(
+            CorsLayer::new()
+                .allow_origin("http://localhost:5173".parse::<HeaderValue>().unwrap())
+                .allow_credentials(true)
+                .allow_headers([COOKIE, CONTENT_TYPE])
+                .allow_methods([Method::GET, Method::POST]),
+        )
+        .layer(Extension(pool))
+}
+
+fn firmware_dir() -> String {
+    std::env::var("FIRMWARE_DIR").unwrap_or("firmware".to_string())
+}
+
+/// Builds the `Update` command for the device's pending update, if any.
+pub async fn pending_update(pool: &SqlitePool, device_id: i64) -> anyhow::Result<Option<WsOutputData>> {
+    let row = sqlx::query!(
+        r#"
+        SELECT f.firmware_id, f.version, f.sha256, f.size
+            FROM firmware_update u
+            INNER JOIN firmware f ON f.firmware_id = u.firmware_id
+            WHERE u.device_id = ? AND u.status NOT IN ('success', 'failed')
+        "#,
+        device_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let public_url = std::env::var("PUBLIC_URL")
+        .unwrap_or(format!("http://localhost:{}", env_or("PORT", 3000)));
+    Ok(row.map(|row| WsOutputData::Update {
+        url: format!(
+            "{}/firmware/{}/download?device_id={}",
+            public_url, row.firmware_id, device_id
+        ),
+        version: row.version,
+        sha256: row.sha256,
+        size: row.size as u64,
+    }))
+}
+
+/// Sends the pending update to the device if it is connected. Devices that do
+/// not advertise the command would never see it, so it stays pending.
+async fn push_update(pool: &SqlitePool, hub: &DeviceHub, device_id: i64) -> anyhow::Result<()> {
+    let msg = match pending_update(pool, device_id).await? {
+        Some(msg) => msg,
+        None => return Ok(()),
+    };
+    if let Some(capability) = msg.required_capability() {
+        if !telemetry::capabilities(pool, device_id).await.contains(capability) {
+            return Ok(());
+        }
+    }
+
+    if hub.send(device_id, msg).await.is_ok() {
+        sqlx::query!(
+            "UPDATE firmware_update SET status = 'sent', updated_at = datetime('now') WHERE device_id = ?",
+            device_id
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Called after the device's hello. Completes the update when the device now
+/// runs the target version, otherwise returns the command to (re)send.
+pub async fn on_hello(
+    pool: &SqlitePool,
+    device_id: i64,
+    firmware_version: &str,
+) -> anyhow::Result<Option<WsOutputData>> {
+    sqlx::query!(
+        r#"
+        UPDATE firmware_update SET status = 'success', progress = 100, error = NULL, updated_at = datetime('now')
+            WHERE device_id = ?
+            AND firmware_id IN (SELECT firmware_id FROM firmware WHERE version = ?)
+        "#,
+        device_id,
+        firmware_version,
+    )
+    .execute(pool)
+    .await?;
+
+    pending_update(pool, device_id).await
+}
+
+pub async fn record_update_status(
+    conn: &mut SqliteConnection,
+    device_id: i64,
+    status: UpdateStatus,
+) -> anyhow::Result<()> {
+    let state = status.status.as_str();
+    let progress = match status.status {
+        UpdateState::Success => 100,
+        _ => status.progress.unwrap_or(0).min(100),
+    };
+    sqlx::query!(
+        r#"
+        UPDATE firmware_update SET status = ?, progress = ?, error = ?, updated_at = datetime('now')
+            WHERE device_id = ?
+            AND firmware_id IN (SELECT firmware_id FROM firmware WHERE version = ?)
+        "#,
+        state,
+        progress,
+        status.error,
+        device_id,
+        status.version,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+async fn get_firmware_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+) -> Result<Json<Vec<Firmware>>, (StatusCode, String)> {
+    let res = get_firmware_service(pool, jwt_auth.id).await.map_err(|e| {
+        let err = e.to_string();
+        (StatusCode::INTERNAL_SERVER_ERROR, err)
+    })?;
+
+    Ok(Json(res))
+}
+
+async fn get_firmware_service(pool: SqlitePool, user_id: u32) -> anyhow::Result<Vec<Firmware>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT firmware_id as "firmware_id!", version, hardware_model, sha256, size, created_at
+            FROM firmware WHERE owner_id = ? ORDER BY created_at DESC
+        "#,
+        user_id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let mut res = vec![];
+
+    for row in rows {
+        res.push(Firmware {
+            id: row.firmware_id,
+            version: row.version,
+            hardware_model: row.hardware_model,
+            sha256: row.sha256,
+            size: row.size,
+            created_at: row.created_at.to_string(),
+        });
+    }
+
+    Ok(res)
+}
+
+async fn upload_firmware_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Query(query): Query<UploadFirmwareQuery>,
+    body: Bytes,
+) -> Result<Json<Firmware>, (StatusCode, String)> {
+    let res = upload_firmware_service(pool, jwt_auth.id, query, body)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn upload_firmware_service(
+    pool: SqlitePool,
+    user_id: u32,
+    query: UploadFirmwareQuery,
+    body: Bytes,
+) -> anyhow::Result<Firmware> {
+    if body.is_empty() {
+        return Err(anyhow::Error::msg("Firmware image is empty"));
+    }
+
+    let sha256 = format!("{:x}", Sha256::digest(&body));
+    let dir = firmware_dir();
+    tokio::fs::create_dir_all(&dir).await?;
+    let path = format!("{}/{}.bin", dir, sha256);
+    tokio::fs::write(&path, &body).await?;
+
+    let size = body.len() as i64;
+    let res = sqlx::query!(
+        r#"
+        INSERT INTO firmware(owner_id, version, hardware_model, sha256, size, path)
+        VALUES (?, ?, ?, ?, ?, ?) RETURNING *
+        "#,
+        user_id,
+        query.version,
+        query.hardware_model,
+        sha256,
+        size,
+        path,
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    Ok(Firmware {
+        id: res.firmware_id,
+        version: res.version,
+        hardware_model: res.hardware_model,
+        sha256: res.sha256,
+        size: res.size,
+        created_at: res.created_at.to_string(),
+    })
+}
+
+/// Resolves the devices a rollout or rollback targets, limited to the user's.
+async fn target_devices(
+    pool: &SqlitePool,
+    user_id: u32,
+    device_ids: Option<Vec<i64>>,
+    room_id: Option<i64>,
+) -> anyhow::Result<Vec<i64>> {
+    let mut res = device_ids.unwrap_or_default();
+
+    if let Some(room_id) = room_id {
+        let rows = sqlx::query_scalar!(
+            "SELECT device_id FROM device WHERE room_id = ? AND owner_id = ?",
+            room_id,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?;
+        res.extend(rows);
+    }
+
+    let mut owned = vec![];
+    for device_id in res {
+        let found = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM device WHERE device_id = ? AND owner_id = ?",
+            device_id,
+            user_id
+        )
+        .fetch_one(pool)
+        .await?;
+        if found > 0 && !owned.contains(&device_id) {
+            owned.push(device_id);
+        }
+    }
+
+    Ok(owned)
+}
+
+async fn rollout_controller(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(hub): Extension<DeviceHub>,
+    jwt_auth: JWTAuth,
+    Json(rollout_dto): Json<RolloutDto>,
+) -> Result<Json<RolloutResult>, (StatusCode, String)> {
+    let res = rollout_service(pool, hub, jwt_auth.id, rollout_dto)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn rollout_service(
+    pool: SqlitePool,
+    hub: DeviceHub,
+    user_id: u32,
+    dto: RolloutDto,
+) -> anyhow::Result<RolloutResult> {
+    let firmware = sqlx::query!(
+        "SELECT hardware_model FROM firmware WHERE firmware_id = ? AND owner_id = ?",
+        dto.firmware_id,
+        user_id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or(anyhow::Error::msg("Firmware not found"))?;
+
+    let devices = target_devices(&pool, user_id, dto.device_ids, dto.room_id).await?;
+    let mut res = RolloutResult {
+        scheduled: vec![],
+        skipped: vec![],
+    };
+
+    for device_id in devices {
+        // Only flash images built for the hardware the device reported, on
+        // devices that can install them.
+        let model = sqlx::query_scalar!(
+            "SELECT hardware_model FROM device_info WHERE device_id = ?",
+            device_id
+        )
+        .fetch_optional(&pool)
+        .await?;
+        if model.as_deref() != Some(firmware.hardware_model.as_str())
+            || !telemetry::capabilities(&pool, device_id).await.contains("ota")
+        {
+            res.skipped.push(device_id);
+            continue;
+        }
+
+        schedule_update(&pool, user_id, device_id, dto.firmware_id).await?;
+        push_update(&pool, &hub, device_id).await?;
+        res.scheduled.push(device_id);
+    }
+
+    Ok(res)
+}
+
+/// Targets the device at the firmware, remembering the image it currently
+/// runs according to its hello for a rollback. Devices running firmware that
+/// was never uploaded can't be rolled back.
+async fn schedule_update(pool: &SqlitePool, user_id: u32, device_id: i64, firmware_id: i64) -> anyhow::Result<()> {
+    let running = sqlx::query_scalar!(
+        r#"
+        SELECT f.firmware_id AS "firmware_id!"
+            FROM firmware f
+            INNER JOIN device_info i ON i.hardware_model = f.hardware_model AND i.firmware_version = f.version
+            WHERE i.device_id = ? AND f.owner_id = ?
+        "#,
+        device_id,
+        user_id,
+    )
+    .fetch_optional(pool)
+    .await?;
+    // Installing the running image again keeps what it replaced.
+    let reinstall = running == Some(firmware_id);
+    let previous = running.filter(|_| !reinstall);
+
+    sqlx::query!(
+        r#"
+        INSERT INTO firmware_update(device_id, firmware_id, previous_firmware_id, status, progress, updated_at)
+        VALUES (?, ?, ?, 'pending', 0, datetime('now'))
+        ON CONFLICT(device_id) DO UPDATE SET
+            previous_firmware_id = CASE
+                WHEN ? AND firmware_update.previous_firmware_id != excluded.firmware_id
+                    THEN firmware_update.previous_firmware_id
+                ELSE excluded.previous_firmware_id
+            END,
+            firmware_id = excluded.firmware_id,
+            status = 'pending',
+            progress = 0,
+            error = NULL,
+            updated_at = excluded.updated_at
+        "#,
+        device_id,
+        firmware_id,
+        previous,
+        reinstall,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+async fn rollback_controller(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(hub): Extension<DeviceHub>,
+    jwt_auth: JWTAuth,
+    Json(rollback_dto): Json<RollbackDto>,
+) -> Result<Json<RolloutResult>, (StatusCode, String)> {
+    let res = rollback_service(pool, hub, jwt_auth.id, rollback_dto)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+/// Targets every device back at the firmware it ran before its last rollout.
+async fn rollback_service(
+    pool: SqlitePool,
+    hub: DeviceHub,
+    user_id: u32,
+    dto: RollbackDto,
+) -> anyhow::Result<RolloutResult> {
+    let devices = target_devices(&pool, user_id, dto.device_ids, dto.room_id).await?;
+    let mut res = RolloutResult {
+        scheduled: vec![],
+        skipped: vec![],
+    };
+
+    for device_id in devices {
+        let previous = sqlx::query_scalar!(
+            "SELECT previous_firmware_id FROM firmware_update WHERE device_id = ?",
+            device_id
+        )
+        .fetch_optional(&pool)
+        .await?
+        .flatten();
+        let Some(previous) = previous else {
+            res.skipped.push(device_id);
+            continue;
+        };
+
+        schedule_update(&pool, user_id, device_id, previous).await?;
+        push_update(&pool, &hub, device_id).await?;
+        res.scheduled.push(device_id);
+    }
+
+    Ok(res)
+}
+
+async fn get_rollout_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Query(filter): Query<RolloutFilter>,
+) -> Result<Json<Vec<DeviceUpdate>>, (StatusCode, String)> {
+    let res = get_rollout_service(pool, jwt_auth.id, filter)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn get_rollout_service(
+    pool: SqlitePool,
+    user_id: u32,
+    filter: RolloutFilter,
+) -> anyhow::Result<Vec<DeviceUpdate>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT u.*, f.version
+            FROM firmware_update u
+            INNER JOIN firmware f ON f.firmware_id = u.firmware_id
+            INNER JOIN device d ON d.device_id = u.device_id
+            WHERE d.owner_id = ? AND (? IS NULL OR u.firmware_id = ?)
+        "#,
+        user_id,
+        filter.firmware_id,
+        filter.firmware_id,
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let mut res = vec![];
+
+    for row in rows {
+        res.push(DeviceUpdate {
+            device_id: row.device_id,
+            firmware_id: row.firmware_id,
+            version: row.version,
+            previous_firmware_id: row.previous_firmware_id,
+            status: row.status,
+            progress: row.progress,
+            error: row.error,
+            updated_at: row.updated_at.to_string(),
+        });
+    }
+
+    Ok(res)
+}
+
+/// Serves the image to a device holding valid credentials that has been
+/// targeted with it.
+async fn download_firmware_controller(
+    Extension(pool): Extension<SqlitePool>,
+    DeviceToken(token): DeviceToken,
+    Path(id): Path<i64>,
+    Query(DownloadQuery { device_id }): Query<DownloadQuery>,
+) -> Result<Vec<u8>, (StatusCode, String)> {
+    let row = sqlx::query!(
+        r#"
+        SELECT f.path, d.secret_hash
+            FROM firmware_update u
+            INNER JOIN firmware f ON f.firmware_id = u.firmware_id
+            INNER JOIN device d ON d.device_id = u.device_id
+            WHERE u.device_id = ? AND u.firmware_id = ?
+        "#,
+        device_id,
+        id,
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .ok_or((StatusCode::NOT_FOUND, "Firmware not found".to_string()))?;
+
+    if !verify_token(token.as_deref(), row.secret_hash.as_deref()) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "Invalid device credentials".to_string(),
+        ));
+    }
+
+    let image = tokio::fs::read(&row.path)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(image)
+}
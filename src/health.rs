@@ -0,0 +1,172 @@
+use crate::events::{self, DeviceEvent};
+use crate::ingest::TempReading;
+use crate::utils::config::env_or;
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    Ok,
+    Warning,
+    Fault,
+}
+
+impl Health {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Health::Ok => "ok",
+            Health::Warning => "warning",
+            Health::Fault => "fault",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthIssue {
+    LowBattery,
+    /// The sensor kept reporting exactly the same temperature.
+    StuckReading,
+    TemperatureOutOfRange,
+    HumidityOutOfRange,
+}
+
+impl HealthIssue {
+    pub fn severity(&self) -> Health {
+        match self {
+            HealthIssue::LowBattery => Health::Warning,
+            HealthIssue::StuckReading
+            | HealthIssue::TemperatureOutOfRange
+            | HealthIssue::HumidityOutOfRange => Health::Fault,
+        }
+    }
+}
+
+/// Flags readings no working sensor can produce and sensors that stopped changing.
+pub async fn check_readings(
+    pool: &SqlitePool,
+    device_id: i64,
+    readings: &[TempReading],
+) -> anyhow::Result<()> {
+    let min_temp = env_or("SENSOR_MIN_TEMPERATURE", -40.0);
+    let max_temp = env_or("SENSOR_MAX_TEMPERATURE", 85.0);
+    let temp_out_of_range = readings
+        .iter()
+        .any(|r| r.temp < min_temp || r.temp > max_temp);
+    let hum_out_of_range = readings.iter().any(|r| !(0.0..=100.0).contains(&r.hum));
+
+    // Filtered readings repeat the previous temperature, only what the sensor
+    // actually reported tells whether it is stuck.
+    let stuck_count = env_or("STUCK_READINGS_COUNT", 10i64);
+    let recent = sqlx::query_scalar!(
+        r#"
+        SELECT raw_temperature AS "raw_temperature!" FROM room_history
+            WHERE device_id = ? AND raw_temperature IS NOT NULL
+            ORDER BY created_at DESC LIMIT ?
+        "#,
+        device_id,
+        stuck_count,
+    )
+    .fetch_all(pool)
+    .await?;
+    let stuck = recent.len() as i64 == stuck_count && recent.windows(2).all(|w| w[0] == w[1]);
+
+    update_issues(
+        pool,
+        device_id,
+        &[
+            (HealthIssue::TemperatureOutOfRange, temp_out_of_range),
+            (HealthIssue::HumidityOutOfRange, hum_out_of_range),
+            (HealthIssue::StuckReading, stuck),
+        ],
+    )
+    .await
+}
+
+pub async fn check_battery(pool: &SqlitePool, device_id: i64, voltage: f64) -> anyhow::Result<()> {
+    let low = voltage < env_or("LOW_BATTERY_VOLTAGE", 3.3);
+    update_issues(pool, device_id, &[(HealthIssue::LowBattery, low)]).await
+}
+
+/// Raises or clears `changes` on the device, recomputes the health of the
+/// device and its room, and emits an alert for every newly raised issue.
+async fn update_issues(
+    pool: &SqlitePool,
+    device_id: i64,
+    changes: &[(HealthIssue, bool)],
+) -> anyhow::Result<()> {
+    let device = sqlx::query!(
+        "SELECT room_id, health_issues FROM device WHERE device_id = ?",
+        device_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    let device = match device {
+        Some(device) => device,
+        None => return Ok(()),
+    };
+
+    let mut issues: Vec<HealthIssue> = serde_json::from_str(&device.health_issues).unwrap_or_default();
+    let before = issues.clone();
+    for (issue, present) in changes {
+        let known = issues.contains(issue);
+        if *present && !known {
+            issues.push(*issue);
+            events::emit(DeviceEvent::Alert {
+                device_id,
+                room_id: device.room_id,
+                issue: *issue,
+            });
+        } else if !*present && known {
+            issues.retain(|i| i != issue);
+        }
+    }
+    if issues == before {
+        return Ok(());
+    }
+
+    let health = issues
+        .iter()
+        .map(|i| i.severity())
+        .max()
+        .unwrap_or(Health::Ok)
+        .as_str();
+    let health_issues = serde_json::to_string(&issues)?;
+    sqlx::query!(
+        "UPDATE device SET health = ?, health_issues = ? WHERE device_id = ?",
+        health,
+        health_issues,
+        device_id,
+    )
+    .execute(pool)
+    .await?;
+
+    if let Some(room_id) = device.room_id {
+        refresh_room_health(&mut *pool.acquire().await?, room_id).await?;
+    }
+
+    Ok(())
+}
+
+/// A room is as healthy as its least healthy device.
+pub async fn refresh_room_health(conn: &mut SqliteConnection, room_id: i64) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE room SET health = COALESCE((
+            SELECT CASE MAX(CASE health WHEN 'fault' THEN 2 WHEN 'warning' THEN 1 ELSE 0 END)
+                WHEN 2 THEN 'fault'
+                WHEN 1 THEN 'warning'
+                ELSE 'ok'
+            END
+            FROM device WHERE device.room_id = room.room_id
+        ), 'ok')
+        WHERE room_id = ?
+        "#,
+        room_id
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
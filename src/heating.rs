@@ -0,0 +1,229 @@
+use crate::actuator;
+use crate::event_log::CONTACT;
+use crate::hub::DeviceHub;
+use crate::utils::config::env_or;
+use crate::window;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Heating is paused while a door or window contact of the room is open.
+pub const PAUSED_BY_CONTACT: &str = "contact";
+/// Heating is paused for a while after a sharp temperature drop.
+pub const PAUSED_BY_WINDOW: &str = "window_open";
+
+/// Resumes heating once timed pauses run out.
+pub async fn run(pool: SqlitePool, hub: DeviceHub) {
+    let interval = Duration::from_millis(env_or("HEATING_CHECK_INTERVAL_MS", 30000u64).max(1));
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = resume_expired(&pool, &hub).await {
+            println!("Failed to resume heating: {}", e);
+        }
+        if let Err(e) = window::close_expired(&pool).await {
+            println!("Failed to close open windows: {}", e);
+        }
+    }
+}
+
+/// Pauses heating of the room for `reason`, switching off its heating
+/// actuators and remembering their desired state for `resume`. `until` is a
+/// Unix epoch in seconds after which the reason expires on its own, `None`
+/// pauses until resumed. Pausing again for the same reason moves its expiry.
+pub async fn pause(
+    pool: &SqlitePool,
+    hub: &DeviceHub,
+    room_id: i64,
+    reason: &str,
+    until: Option<i64>,
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    let paused = sqlx::query_scalar!("SELECT COUNT(*) FROM heating_pause WHERE room_id = ?", room_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO heating_pause(room_id, reason, until) VALUES (?, ?, datetime(?, 'unixepoch'))
+            ON CONFLICT(room_id, reason) DO UPDATE SET until = excluded.until
+        "#,
+        room_id,
+        reason,
+        until,
+    )
+    .execute(&mut *tx)
+    .await?;
+    // Actuators are already off if another reason paused the room first.
+    if paused > 0 {
+        tx.commit().await?;
+        return Ok(());
+    }
+
+    let actuators = sqlx::query_scalar!(
+        r#"
+        UPDATE actuator SET paused_state = COALESCE(desired_state, reported_state)
+            WHERE heating AND actuator_id IN (
+                SELECT a.actuator_id FROM actuator a
+                    INNER JOIN device d ON d.device_id = a.device_id
+                    WHERE COALESCE(a.room_id, d.room_id) = ?
+            )
+            RETURNING actuator_id AS "actuator_id!"
+        "#,
+        room_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    println!("Paused heating of room {} ({})", room_id, reason);
+    let commands = actuators.into_iter().map(|actuator_id| (actuator_id, 0.0)).collect();
+    command_all(pool, hub, commands).await
+}
+
+/// Lifts the pause for `reason`, restoring the heating actuators of the room
+/// once no other reason is left.
+pub async fn resume(pool: &SqlitePool, hub: &DeviceHub, room_id: i64, reason: &str) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    let res = sqlx::query!(
+        "DELETE FROM heating_pause WHERE room_id = ? AND reason = ?",
+        room_id,
+        reason,
+    )
+    .execute(&mut *tx)
+    .await?;
+    let remaining = sqlx::query_scalar!("SELECT COUNT(*) FROM heating_pause WHERE room_id = ?", room_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if res.rows_affected() == 0 || remaining > 0 {
+        tx.commit().await?;
+        return Ok(());
+    }
+
+    let actuators = sqlx::query!(
+        r#"
+        SELECT a.actuator_id AS "actuator_id!", a.paused_state AS "paused_state!"
+            FROM actuator a
+            INNER JOIN device d ON d.device_id = a.device_id
+            WHERE a.heating AND a.paused_state IS NOT NULL AND COALESCE(a.room_id, d.room_id) = ?
+        "#,
+        room_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        UPDATE actuator SET paused_state = NULL
+            WHERE actuator_id IN (
+                SELECT a.actuator_id FROM actuator a
+                    INNER JOIN device d ON d.device_id = a.device_id
+                    WHERE COALESCE(a.room_id, d.room_id) = ?
+            )
+        "#,
+        room_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    println!("Resumed heating of room {}", room_id);
+    let commands = actuators.into_iter().map(|row| (row.actuator_id, row.paused_state)).collect();
+    command_all(pool, hub, commands).await
+}
+
+/// Commands every actuator, even if some of them fail. The pause is already
+/// committed, so stopping halfway would leave the rest in the wrong state.
+async fn command_all(pool: &SqlitePool, hub: &DeviceHub, commands: Vec<(i64, f64)>) -> anyhow::Result<()> {
+    let mut failed = vec![];
+    for (actuator_id, value) in commands {
+        if let Err(e) = actuator::command(pool, hub, actuator_id, value).await {
+            println!("Failed to command actuator {}: {}", actuator_id, e);
+            failed.push(actuator_id.to_string());
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(anyhow::Error::msg(format!(
+            "Failed to command actuators {}",
+            failed.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+/// Reasons heating of each of the user's rooms is paused for.
+pub async fn pause_reasons(pool: &SqlitePool, user_id: u32) -> anyhow::Result<HashMap<i64, Vec<String>>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT p.room_id, p.reason
+            FROM heating_pause p
+            INNER JOIN room r ON r.room_id = p.room_id
+            WHERE r.owner_id = ?
+            ORDER BY p.created_at
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut res: HashMap<i64, Vec<String>> = HashMap::new();
+    for row in rows {
+        res.entry(row.room_id).or_default().push(row.reason);
+    }
+
+    Ok(res)
+}
+
+/// Lifts every pause whose time ran out.
+pub async fn resume_expired(pool: &SqlitePool, hub: &DeviceHub) -> anyhow::Result<()> {
+    let expired = sqlx::query!("SELECT room_id, reason FROM heating_pause WHERE until <= datetime('now')")
+        .fetch_all(pool)
+        .await?;
+
+    for pause in expired {
+        resume(pool, hub, pause.room_id, &pause.reason).await?;
+    }
+
+    Ok(())
+}
+
+/// Pauses heating when a contact of the room opens, and resumes it once all
+/// of them are closed again. Rooms can opt out with
+/// `pause_heating_on_contact`.
+pub async fn on_contact(pool: &SqlitePool, hub: &DeviceHub, room_id: i64, open: bool) -> anyhow::Result<()> {
+    let enabled = sqlx::query_scalar!(
+        "SELECT pause_heating_on_contact FROM room WHERE room_id = ?",
+        room_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .unwrap_or(false);
+    if !enabled {
+        return Ok(());
+    }
+
+    if open {
+        return pause(pool, hub, room_id, PAUSED_BY_CONTACT, None).await;
+    }
+
+    // The latest contact event of every device in the room tells whether it
+    // is still open.
+    let still_open = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) FROM device_event e
+            WHERE e.room_id = ? AND e.kind = ? AND e.value > 0
+            AND e.event_id = (
+                SELECT MAX(event_id) FROM device_event WHERE device_id = e.device_id AND kind = e.kind
+            )
+        "#,
+        room_id,
+        CONTACT,
+    )
+    .fetch_one(pool)
+    .await?;
+    if still_open > 0 {
+        return Ok(());
+    }
+
+    resume(pool, hub, room_id, PAUSED_BY_CONTACT).await
+}
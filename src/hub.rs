@@ -0,0 +1,263 @@
+use crate::esp_websockets::WsOutputData;
+use crate::utils::config::env_or;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Receiver, Sender, WeakSender};
+
+/// What happens when a device connects while it still has a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// The new connection is refused.
+    Reject,
+    /// The old connection is closed in favour of the new one.
+    Replace,
+    /// Both stay connected and receive every message.
+    Multiple,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(DuplicatePolicy::Reject),
+            "replace" => Ok(DuplicatePolicy::Replace),
+            "multiple" => Ok(DuplicatePolicy::Multiple),
+            _ => Err(anyhow::Error::msg("Unknown duplicate connection policy")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HubError {
+    AlreadyConnected,
+    NotConnected,
+    /// The device did not drain its queue within the send timeout.
+    Full,
+}
+
+impl fmt::Display for HubError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            HubError::AlreadyConnected => "Device is already connected",
+            HubError::NotConnected => "Device is not connected",
+            HubError::Full => "Device is not keeping up with its messages",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for HubError {}
+
+struct Entry {
+    generation: u64,
+    tx: Sender<WsOutputData>,
+}
+
+/// Registry of the live connections of every device, whatever transport they
+/// use. Cheap to clone, all clones share the same state.
+#[derive(Clone)]
+pub struct DeviceHub {
+    devices: Arc<Mutex<HashMap<i64, Vec<Entry>>>>,
+    next_generation: Arc<AtomicU64>,
+    policy: DuplicatePolicy,
+    capacity: usize,
+    send_timeout: Duration,
+}
+
+/// A registered connection. Messages for the device arrive through `recv`,
+/// which returns `None` once the connection has been replaced.
+pub struct Connection {
+    pub generation: u64,
+    /// The device had no other connection, i.e. it just came online.
+    pub first: bool,
+    tx: WeakSender<WsOutputData>,
+    rx: Receiver<WsOutputData>,
+}
+
+impl Connection {
+    pub async fn recv(&mut self) -> Option<WsOutputData> {
+        self.rx.recv().await
+    }
+
+    /// Queues a message for this connection only.
+    pub fn queue(&self, msg: WsOutputData) {
+        if let Some(tx) = self.tx.upgrade() {
+            _ = tx.try_send(msg);
+        }
+    }
+}
+
+impl DeviceHub {
+    pub fn new(policy: DuplicatePolicy, capacity: usize, send_timeout: Duration) -> Self {
+        DeviceHub {
+            devices: Arc::new(Mutex::new(HashMap::new())),
+            next_generation: Arc::new(AtomicU64::new(1)),
+            policy,
+            capacity,
+            send_timeout,
+        }
+    }
+
+    pub fn from_env() -> Self {
+        DeviceHub::new(
+            env_or("DEVICE_DUPLICATE_POLICY", DuplicatePolicy::Replace),
+            env_or("DEVICE_QUEUE_CAPACITY", 32usize).max(1),
+            Duration::from_millis(env_or("DEVICE_SEND_TIMEOUT_MS", 1000)),
+        )
+    }
+
+    pub fn register(&self, device_id: i64) -> Result<Connection, HubError> {
+        let mut devices = self.devices.lock().unwrap();
+        let entries = devices.entry(device_id).or_default();
+        entries.retain(|entry| !entry.tx.is_closed());
+        let first = entries.is_empty();
+
+        if !first {
+            match self.policy {
+                DuplicatePolicy::Reject => return Err(HubError::AlreadyConnected),
+                // Dropping the sender ends the old connection's `recv`.
+                DuplicatePolicy::Replace => entries.clear(),
+                DuplicatePolicy::Multiple => {}
+            }
+        }
+
+        let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(self.capacity);
+        let connection = Connection {
+            generation,
+            first,
+            tx: tx.downgrade(),
+            rx,
+        };
+        entries.push(Entry { generation, tx });
+
+        Ok(connection)
+    }
+
+    /// Removes the connection, unless it has already been replaced. Returns
+    /// whether it was the device's last connection, i.e. the device went
+    /// offline.
+    pub fn unregister(&self, device_id: i64, generation: u64) -> bool {
+        let mut devices = self.devices.lock().unwrap();
+        let Some(entries) = devices.get_mut(&device_id) else {
+            return false;
+        };
+
+        let before = entries.len();
+        entries.retain(|entry| entry.generation != generation);
+        if entries.len() == before || !entries.is_empty() {
+            return false;
+        }
+
+        devices.remove(&device_id);
+        true
+    }
+
+    pub fn is_connected(&self, device_id: i64) -> bool {
+        self.devices.lock().unwrap().contains_key(&device_id)
+    }
+
+    pub fn connected(&self) -> Vec<i64> {
+        let mut res: Vec<i64> = self.devices.lock().unwrap().keys().copied().collect();
+        res.sort();
+        res
+    }
+
+    /// Delivers the message to every connection of the device, waiting up to
+    /// the send timeout for room in each queue.
+    pub async fn send(&self, device_id: i64, msg: WsOutputData) -> Result<(), HubError> {
+        let senders: Vec<Sender<WsOutputData>> = match self.devices.lock().unwrap().get(&device_id) {
+            Some(entries) => entries.iter().map(|entry| entry.tx.clone()).collect(),
+            None => return Err(HubError::NotConnected),
+        };
+
+        let mut res = Err(HubError::Full);
+        for tx in senders {
+            if tx.send_timeout(msg.clone(), self.send_timeout).await.is_ok() {
+                res = Ok(());
+            }
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hub(policy: DuplicatePolicy) -> DeviceHub {
+        DeviceHub::new(policy, 1, Duration::from_millis(10))
+    }
+
+    fn msg() -> WsOutputData {
+        WsOutputData::Settings { presence_timeout: 1 }
+    }
+
+    #[test]
+    fn reject_refuses_second_connection() {
+        let hub = hub(DuplicatePolicy::Reject);
+        let first = hub.register(5).unwrap();
+        assert!(first.first);
+
+        assert_eq!(hub.register(5).err(), Some(HubError::AlreadyConnected));
+        assert!(hub.unregister(5, first.generation));
+        assert!(!hub.is_connected(5));
+    }
+
+    #[tokio::test]
+    async fn replace_closes_old_connection() {
+        let hub = hub(DuplicatePolicy::Replace);
+        let mut old = hub.register(5).unwrap();
+        let mut new = hub.register(5).unwrap();
+        assert!(!new.first);
+
+        assert!(old.recv().await.is_none());
+        hub.send(5, msg()).await.unwrap();
+        assert!(new.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn multiple_delivers_to_every_connection() {
+        let hub = hub(DuplicatePolicy::Multiple);
+        let mut a = hub.register(5).unwrap();
+        let mut b = hub.register(5).unwrap();
+
+        hub.send(5, msg()).await.unwrap();
+        assert!(a.recv().await.is_some());
+        assert!(b.recv().await.is_some());
+
+        assert!(!hub.unregister(5, a.generation));
+        assert!(hub.is_connected(5));
+        assert!(hub.unregister(5, b.generation));
+        assert!(!hub.is_connected(5));
+    }
+
+    #[test]
+    fn stale_unregister_keeps_newer_connection() {
+        let hub = hub(DuplicatePolicy::Replace);
+        let old = hub.register(5).unwrap();
+        let new = hub.register(5).unwrap();
+
+        assert!(!hub.unregister(5, old.generation));
+        assert!(hub.is_connected(5));
+        assert!(hub.unregister(5, new.generation));
+        // Already gone, the device must not go offline twice.
+        assert!(!hub.unregister(5, new.generation));
+    }
+
+    #[tokio::test]
+    async fn send_times_out_on_full_queue() {
+        let hub = hub(DuplicatePolicy::Replace);
+        let _connection = hub.register(5).unwrap();
+
+        hub.send(5, msg()).await.unwrap();
+        assert_eq!(hub.send(5, msg()).await, Err(HubError::Full));
+        assert_eq!(hub.send(6, msg()).await, Err(HubError::NotConnected));
+    }
+}
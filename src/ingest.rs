@@ -0,0 +1,557 @@
+use crate::actuator;
+use crate::aggregation;
+use crate::calibration::Calibrations;
+use crate::diagnostics;
+use crate::event_log;
+use crate::events::{self, DeviceEvent};
+use crate::esp_websockets::{WsInputData, WsInnerData};
+use crate::firmware;
+use crate::health;
+use crate::hub::DeviceHub;
+use crate::maintenance;
+use crate::metric;
+use crate::utils::config::env_or;
+use crate::window;
+use serde::Deserialize;
+use sqlx::{SqliteConnection, SqlitePool};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Single reading buffered on the device, stamped with the device's clock.
+#[derive(Debug, Deserialize)]
+pub struct TempReading {
+    /// Unix epoch in milliseconds.
+    pub ts: i64,
+    pub temp: f64,
+    pub hum: f64,
+    pub wh: f64,
+}
+
+/// Reading of any metric registered in `metric_type`.
+#[derive(Debug, Deserialize)]
+pub struct MetricReading {
+    pub name: String,
+    pub value: f64,
+    /// Must match the registered unit when given.
+    pub unit: Option<String>,
+    /// Unix epoch in milliseconds, defaults to the time of arrival.
+    pub ts: Option<i64>,
+}
+
+pub fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Reading the server refuses to store, as opposed to one it failed to store.
+/// Sending it again does not help.
+#[derive(Debug)]
+pub struct InvalidReading(pub String);
+
+impl fmt::Display for InvalidReading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidReading {}
+
+/// Readings as stored, with the room values they produced.
+pub struct Batch {
+    readings: Vec<TempReading>,
+    telemetry: Option<DeviceEvent>,
+}
+
+/// Work left once a message is committed. The message is stored by then, so
+/// failures are only logged instead of making the device send it again.
+enum FollowUp {
+    None,
+    Readings(Batch),
+    Battery(f64),
+    State { channel: u32, booked: Option<i64> },
+    Event(event_log::RecordedEvent),
+    Presence(DeviceEvent),
+}
+
+/// Stores a message received from a device, whatever transport it arrived
+/// over. Returns `false` for messages that are not acknowledged, and an error
+/// if the message could not be stored, so the device can send it again.
+///
+/// The sequence number is recorded in the same transaction as the message, so
+/// a message that failed to store is not taken for a duplicate when resent.
+pub async fn process_input(
+    pool: &SqlitePool,
+    hub: &DeviceHub,
+    device_id: i64,
+    data: WsInputData,
+) -> anyhow::Result<bool> {
+    mark_seen(pool, device_id).await?;
+
+    let mut tx = pool.begin().await?;
+    if let Some(seq) = data.seq {
+        // Duplicates are still acknowledged so the device stops re-sending them.
+        if !accept_sequence(&mut tx, device_id, seq).await? {
+            tx.commit().await?;
+            return Ok(true);
+        }
+    }
+
+    let follow_up = match store(&mut tx, device_id, data.inner).await {
+        Ok(Some(follow_up)) => follow_up,
+        Ok(None) => {
+            tx.commit().await?;
+            return Ok(false);
+        }
+        // Invalid readings are acknowledged as well, and keep what they
+        // changed, e.g. a spike streak.
+        Err(e) if e.is::<InvalidReading>() => {
+            tx.commit().await?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    tx.commit().await?;
+
+    if let Err(e) = follow_up.run(pool, hub, device_id).await {
+        println!("Failed to handle stored message of client {}: {}", device_id, e);
+    }
+    Ok(true)
+}
+
+/// Records that the device is alive, whatever transport it uses.
+pub async fn mark_seen(pool: &SqlitePool, device_id: i64) -> anyhow::Result<()> {
+    let now = now_ms();
+    sqlx::query!("UPDATE device SET last_seen_ms = ? WHERE device_id = ?", now, device_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Returns `None` for messages that are not acknowledged.
+async fn store(
+    conn: &mut SqliteConnection,
+    device_id: i64,
+    inner: WsInnerData,
+) -> anyhow::Result<Option<FollowUp>> {
+    let follow_up = match inner {
+        WsInnerData::Temp { temp, hum, wh } => FollowUp::Readings(record_temp(conn, device_id, temp, hum, wh).await?),
+        WsInnerData::TempBatch { readings } => FollowUp::Readings(record_temp_batch(conn, device_id, readings).await?),
+        WsInnerData::Metric(reading) => {
+            record_metric(conn, device_id, reading).await?;
+            FollowUp::None
+        }
+        WsInnerData::Diagnostics(report) => {
+            let battery_voltage = report.battery_voltage;
+            diagnostics::record_diagnostics(conn, device_id, report).await?;
+            battery_voltage.map_or(FollowUp::None, FollowUp::Battery)
+        }
+        WsInnerData::Log(line) => {
+            diagnostics::record_log(conn, device_id, line).await?;
+            FollowUp::None
+        }
+        WsInnerData::UpdateStatus(status) => {
+            firmware::record_update_status(conn, device_id, status).await?;
+            FollowUp::None
+        }
+        WsInnerData::State(report) => {
+            let channel = report.channel;
+            let booked = actuator::record_state(conn, device_id, report).await?;
+            FollowUp::State { channel, booked }
+        }
+        WsInnerData::Event(report) => FollowUp::Event(event_log::record_event(conn, device_id, report).await?),
+        WsInnerData::Move => record_presence(conn, device_id).await?.map_or(FollowUp::None, FollowUp::Presence),
+        WsInnerData::Heartbeat | WsInnerData::Hello(_) | WsInnerData::TimeRequest => return Ok(None),
+    };
+
+    Ok(Some(follow_up))
+}
+
+impl FollowUp {
+    async fn run(self, pool: &SqlitePool, hub: &DeviceHub, device_id: i64) -> anyhow::Result<()> {
+        match self {
+            FollowUp::None => {}
+            FollowUp::Readings(batch) => {
+                if let Some(telemetry) = batch.telemetry {
+                    events::emit(telemetry);
+                }
+                detect_open_window(pool, hub, device_id).await;
+                // Health checks look at the raw readings, filtered ones included.
+                if !batch.readings.is_empty() {
+                    health::check_readings(pool, device_id, &batch.readings).await?;
+                }
+            }
+            FollowUp::Battery(voltage) => health::check_battery(pool, device_id, voltage).await?,
+            FollowUp::State { channel, booked } => {
+                actuator::emit_state(pool, device_id, channel).await?;
+                if let Some(actuator_id) = booked {
+                    maintenance::check(pool, actuator_id).await?;
+                }
+            }
+            FollowUp::Event(event) => event_log::react(pool, hub, device_id, event).await?,
+            FollowUp::Presence(presence) => events::emit(presence),
+        }
+
+        Ok(())
+    }
+}
+
+async fn detect_open_window(pool: &SqlitePool, hub: &DeviceHub, device_id: i64) {
+    if let Err(e) = window::check(pool, hub, device_id).await {
+        println!("Failed to check for open window of client {}: {}", device_id, e);
+    }
+}
+
+/// Readings are attributed to the device and to the room it is currently
+/// assigned to. Devices without a room are not recorded.
+pub async fn record_temp(
+    conn: &mut SqliteConnection,
+    device_id: i64,
+    temp: f64,
+    hum: f64,
+    wh: f64,
+) -> anyhow::Result<Batch> {
+    let reading = TempReading {
+        ts: now_ms(),
+        temp,
+        hum,
+        wh,
+    };
+
+    record_temp_batch(conn, device_id, vec![reading]).await
+}
+
+/// Stores a batch of device-timestamped readings. Readings
+/// stamped too far in the future are dropped, and the device's latest values
+/// only move forward in time. Calibration is applied before storing, and
+/// values outside the valid range or spiking away from the previous value are
+/// filtered out, each metric on its own. Raw values are kept next to the
+/// corrected ones.
+pub async fn record_temp_batch(
+    conn: &mut SqliteConnection,
+    device_id: i64,
+    readings: Vec<TempReading>,
+) -> anyhow::Result<Batch> {
+    let max_ts = now_ms() + env_or("TELEMETRY_MAX_FUTURE_MS", 60000i64);
+    let (mut readings, rejected): (Vec<_>, Vec<_>) = readings
+        .into_iter()
+        .partition(|r| r.ts > 0 && r.ts <= max_ts);
+    if !rejected.is_empty() {
+        println!(
+            "Rejected {} readings with invalid timestamps from device {}",
+            rejected.len(),
+            device_id
+        );
+    }
+    readings.sort_by_key(|r| r.ts);
+    let mut batch = Batch {
+        readings: vec![],
+        telemetry: None,
+    };
+    if readings.is_empty() {
+        return Ok(batch);
+    }
+
+    let mut calibrations = Calibrations::load(conn, device_id).await?;
+    let last = sqlx::query!(
+        "SELECT last_temperature, last_humidity, last_watthour FROM device WHERE device_id = ?",
+        device_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    let (mut prev_temp, mut prev_hum, mut prev_wh) = match last {
+        Some(last) => (last.last_temperature, last.last_humidity, last.last_watthour),
+        None => return Ok(batch),
+    };
+
+    let mut newest = None;
+    let mut filtered = 0;
+    for reading in &readings {
+        let temp = calibrations.apply("temperature", reading.temp, prev_temp);
+        let hum = calibrations.apply("humidity", reading.hum, prev_hum);
+        let wh = calibrations.apply("watthour", reading.wh, prev_wh);
+        let values = [
+            ("temperature", temp, reading.temp),
+            ("humidity", hum, reading.hum),
+            ("watthour", wh, reading.wh),
+        ];
+        filtered += values.iter().filter(|(_, value, _)| value.is_none()).count();
+        if values.iter().all(|(_, value, _)| value.is_none()) {
+            continue;
+        }
+
+        let ts = reading.ts / 1000;
+        for (metric, value, raw) in values {
+            if let Some(value) = value {
+                insert_sample(conn, device_id, metric, value, raw, ts).await?;
+            }
+        }
+        (prev_temp, prev_hum, prev_wh) = (temp.or(prev_temp), hum.or(prev_hum), wh.or(prev_wh));
+        newest = Some(ts);
+
+        // History rows need all three values. A dropped one keeps the previous
+        // value without a raw reading, so recalibrating leaves it alone.
+        let (Some(history_temp), Some(history_hum), Some(history_wh)) = (prev_temp, prev_hum, prev_wh) else {
+            continue;
+        };
+        let (raw_temp, raw_hum, raw_wh) = (
+            temp.map(|_| reading.temp),
+            hum.map(|_| reading.hum),
+            wh.map(|_| reading.wh),
+        );
+        sqlx::query!(
+            r#"
+            INSERT INTO room_history (room_id, device_id, temperature, humidity, watthour,
+                raw_temperature, raw_humidity, raw_watthour, created_at)
+            SELECT room_id, device_id, ?, ?, ?, ?, ?, ?, datetime(?, 'unixepoch')
+                FROM device
+                WHERE device_id = ? AND room_id IS NOT NULL
+            "#,
+            history_temp,
+            history_hum,
+            history_wh,
+            raw_temp,
+            raw_hum,
+            raw_wh,
+            ts,
+            device_id,
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    calibrations.save(conn).await?;
+    if filtered > 0 {
+        println!("Filtered {} implausible values from device {}", filtered, device_id);
+    }
+
+    if let Some(newest_ts) = newest {
+        sqlx::query!(
+            r#"
+            UPDATE device
+                SET last_temperature = ?, last_humidity = ?, last_watthour = ?,
+                last_reading_at = datetime(?, 'unixepoch')
+                WHERE device_id = ?
+                AND (last_reading_at IS NULL OR last_reading_at <= datetime(?, 'unixepoch'))
+            "#,
+            prev_temp,
+            prev_hum,
+            prev_wh,
+            newest_ts,
+            device_id,
+            newest_ts,
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        let room_id = sqlx::query_scalar!("SELECT room_id FROM device WHERE device_id = ?", device_id)
+            .fetch_one(&mut *conn)
+            .await?;
+        if let Some(room_id) = room_id {
+            aggregation::refresh_room_current(conn, room_id).await?;
+            let room = sqlx::query!(
+                "SELECT current_temperature, current_humidity, current_watthour FROM room WHERE room_id = ?",
+                room_id
+            )
+            .fetch_one(&mut *conn)
+            .await?;
+            batch.telemetry = Some(DeviceEvent::Telemetry {
+                device_id,
+                room_id,
+                temperature: room.current_temperature,
+                humidity: room.current_humidity,
+                watthour: room.current_watthour,
+            });
+        }
+    }
+
+    batch.readings = readings;
+    Ok(batch)
+}
+
+pub async fn record_metric(
+    conn: &mut SqliteConnection,
+    device_id: i64,
+    reading: MetricReading,
+) -> anyhow::Result<()> {
+    let name = metric::normalize_name(&reading.name);
+    let metric = sqlx::query!("SELECT metric_id, unit FROM metric_type WHERE name = ?", name)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(InvalidReading(format!("Unknown metric {}", name)))?;
+    if let Some(unit) = reading.unit {
+        if unit != metric.unit {
+            return Err(InvalidReading(format!(
+                "Metric {} is measured in {}, got {}",
+                name, metric.unit, unit
+            ))
+            .into());
+        }
+    }
+
+    let ts = reading.ts.unwrap_or_else(now_ms);
+    if ts <= 0 || ts > now_ms() + env_or("TELEMETRY_MAX_FUTURE_MS", 60000i64) {
+        return Err(InvalidReading("Invalid timestamp".to_string()).into());
+    }
+
+    let mut calibrations = Calibrations::load(conn, device_id).await?;
+    let previous = sqlx::query_scalar!(
+        "SELECT value FROM metric_sample WHERE device_id = ? AND metric_id = ? ORDER BY created_at DESC LIMIT 1",
+        device_id,
+        metric.metric_id,
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    let value = calibrations.apply(&name, reading.value, previous);
+    calibrations.save(conn).await?;
+    let value = value.ok_or(InvalidReading(format!("Implausible {} reading", name)))?;
+
+    insert_sample(conn, device_id, &name, value, reading.value, ts / 1000).await
+}
+
+async fn insert_sample(
+    conn: &mut SqliteConnection,
+    device_id: i64,
+    metric: &str,
+    value: f64,
+    raw_value: f64,
+    ts: i64,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO metric_sample (room_id, device_id, metric_id, value, raw_value, created_at)
+        SELECT d.room_id, d.device_id, m.metric_id, ?, ?, datetime(?, 'unixepoch')
+            FROM device d, metric_type m
+            WHERE d.device_id = ? AND d.room_id IS NOT NULL AND m.name = ?
+        "#,
+        value,
+        raw_value,
+        ts,
+        device_id,
+        metric,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn record_presence(conn: &mut SqliteConnection, device_id: i64) -> anyhow::Result<Option<DeviceEvent>> {
+    let room = sqlx::query!(
+        r#"
+        UPDATE room SET last_presence = (strftime('%s', 'now'))
+            WHERE room_id = (SELECT room_id FROM device WHERE device_id = ?)
+            RETURNING room_id AS "room_id!", last_presence
+        "#,
+        device_id,
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(room.map(|room| DeviceEvent::Presence {
+        device_id,
+        room_id: room.room_id,
+        lastpresence: room.last_presence,
+    }))
+}
+
+/// Checks a device message's sequence number against the last accepted one.
+/// Returns `false` for duplicates and replays, which must not be ingested again.
+/// Skipped numbers are counted as gaps for diagnostics. Without a boot id the
+/// sequence starts over with every hello.
+pub async fn accept_sequence(conn: &mut SqliteConnection, device_id: i64, seq: i64) -> anyhow::Result<bool> {
+    let accepted = sqlx::query!(
+        r#"
+        UPDATE device_info
+            SET seq_gaps = seq_gaps + COALESCE(? - last_seq - 1, 0), last_seq = ?
+            WHERE device_id = ? AND (last_seq IS NULL OR last_seq < ?)
+            RETURNING device_id
+        "#,
+        seq,
+        seq,
+        device_id,
+        seq,
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    if accepted.is_some() {
+        return Ok(true);
+    }
+
+    // Devices that never said hello have nothing to compare against.
+    let duplicate = sqlx::query!(
+        "UPDATE device_info SET seq_duplicates = seq_duplicates + 1 WHERE device_id = ?",
+        device_id
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(duplicate.rows_affected() == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hub::DuplicatePolicy;
+    use std::time::Duration;
+
+    async fn claimed_device(pool: &SqlitePool) {
+        sqlx::query(
+            r#"
+            INSERT INTO user(user_id, email, password, name, lastname) VALUES (1, 'a@b', '', 'A', 'B');
+            INSERT INTO room(room_id, icon_id, owner_id, room_name) VALUES (1, 1, 1, 'Room');
+            INSERT INTO device(device_id, owner_id, room_id) VALUES (5, 1, 1);
+            INSERT INTO device_info(device_id, protocol_version, firmware_version, hardware_model, last_hello_at)
+                VALUES (5, 1, '1.0.0', 'esp32', datetime('now'));
+            "#,
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    fn temp(seq: i64) -> WsInputData {
+        serde_json::from_value(serde_json::json!({
+            "device_id": 5,
+            "seq": seq,
+            "type": "temp",
+            "data": { "temp": 21.5, "hum": 40.0, "wh": 12.0 },
+        }))
+        .unwrap()
+    }
+
+    async fn history_rows(pool: &SqlitePool) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM room_history WHERE device_id = 5")
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn resend_after_failed_ingest_is_stored_once(pool: SqlitePool) {
+        claimed_device(&pool).await;
+        let hub = DeviceHub::new(DuplicatePolicy::Replace, 8, Duration::from_millis(10));
+
+        sqlx::query("CREATE TRIGGER fail BEFORE INSERT ON room_history BEGIN SELECT RAISE(ABORT, 'disk full'); END")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(process_input(&pool, &hub, 5, temp(1)).await.is_err());
+        assert_eq!(history_rows(&pool).await, 0);
+
+        sqlx::query("DROP TRIGGER fail").execute(&pool).await.unwrap();
+        assert!(process_input(&pool, &hub, 5, temp(1)).await.unwrap());
+        assert_eq!(history_rows(&pool).await, 1);
+
+        // Now it is a duplicate: acknowledged, not stored again.
+        assert!(process_input(&pool, &hub, 5, temp(1)).await.unwrap());
+        assert_eq!(history_rows(&pool).await, 1);
+
+        let last_seq: Option<i64> = sqlx::query_scalar("SELECT last_seq FROM device_info WHERE device_id = 5")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(last_seq, Some(1));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::events::{DeviceEvent, EVENTS};
+use crate::router::FRONTEND_ORIGIN;
+use crate::utils::config::env_or;
+use crate::utils::jwt::JWTAuth;
+use axum::{
+    body::Body,
+    extract::{
+        ws::{Message, WebSocket},
+        WebSocketUpgrade,
+    },
+    http::{header::ORIGIN, HeaderMap, Response, StatusCode},
+    response::IntoResponse,
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::{collections::HashSet, time::Duration};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::MissedTickBehavior;
+
+/// Sent by the browser to choose the rooms it gets updates for.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "data")]
+#[serde(rename_all = "snake_case")]
+enum LiveInput {
+    Subscribe { room_ids: Vec<i64> },
+    Unsubscribe { room_ids: Vec<i64> },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", content = "data")]
+#[serde(rename_all = "snake_case")]
+enum LiveOutput {
+    /// Rooms the connection now gets updates for. Rooms of other users are
+    /// left out.
+    Subscribed { room_ids: Vec<i64> },
+    Update { room_id: i64, event: DeviceEvent },
+    /// Updates were dropped because the connection fell behind, the current
+    /// state has to be fetched from `GET /room` again.
+    Resync,
+}
+
+/// Live updates of the user's rooms for the app, e.g. new readings, presence,
+/// devices going online and actuators switching. Nothing is sent until the
+/// client subscribes to some rooms.
+///
+/// CORS does not cover WebSocket upgrades, so browsers on other sites are
+/// refused here instead of being able to use the session cookie.
+pub async fn live_handler(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    jwt_auth: JWTAuth,
+    Extension(pool): Extension<SqlitePool>,
+) -> Response<Body> {
+    if let Some(origin) = headers.get(ORIGIN) {
+        if origin != FRONTEND_ORIGIN {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+    }
+
+    ws.on_upgrade(move |socket| async move {
+        handle_socket(socket, jwt_auth.id, pool).await;
+    })
+}
+
+async fn handle_socket(mut socket: WebSocket, user_id: u32, pool: SqlitePool) {
+    let mut events = EVENTS.subscribe();
+    let mut room_ids = HashSet::new();
+
+    let ping_interval = Duration::from_millis(env_or("WS_PING_INTERVAL_MS", 15000u64).max(1));
+    let mut ping_ticker = tokio::time::interval(ping_interval);
+    ping_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            msg = socket.recv() => {
+                let msg = match msg {
+                    Some(Ok(Message::Text(msg))) => msg,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => continue,
+                };
+                let Ok(input) = serde_json::from_str::<LiveInput>(&msg) else {
+                    continue;
+                };
+
+                match input {
+                    LiveInput::Subscribe { room_ids: requested } => {
+                        match owned_rooms(&pool, user_id).await {
+                            Ok(owned) => room_ids.extend(requested.into_iter().filter(|id| owned.contains(id))),
+                            Err(e) => println!("Failed to load rooms of user {}: {}", user_id, e),
+                        }
+                    }
+                    LiveInput::Unsubscribe { room_ids: requested } => {
+                        for room_id in requested {
+                            room_ids.remove(&room_id);
+                        }
+                    }
+                }
+
+                let mut subscribed: Vec<_> = room_ids.iter().copied().collect();
+                subscribed.sort();
+                if send(&mut socket, &LiveOutput::Subscribed { room_ids: subscribed }).await.is_err() {
+                    break;
+                }
+            }
+            event = events.recv() => {
+                let msg = match event {
+                    Ok(event) => {
+                        let room_id = match event.room_id() {
+                            Some(room_id) => Some(room_id),
+                            None => device_room(&pool, &event).await,
+                        };
+                        let room_id = match room_id {
+                            Some(room_id) if room_ids.contains(&room_id) => room_id,
+                            _ => continue,
+                        };
+                        // The room may have been deleted since the subscription.
+                        if !owns_room(&pool, user_id, room_id).await {
+                            room_ids.remove(&room_id);
+                            continue;
+                        }
+                        LiveOutput::Update { room_id, event }
+                    }
+                    Err(RecvError::Lagged(_)) => LiveOutput::Resync,
+                    Err(RecvError::Closed) => break,
+                };
+
+                if send(&mut socket, &msg).await.is_err() {
+                    break;
+                }
+            }
+            _ = ping_ticker.tick() => {
+                if socket.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn owned_rooms(pool: &SqlitePool, user_id: u32) -> anyhow::Result<HashSet<i64>> {
+    let rooms = sqlx::query_scalar!("SELECT room_id FROM room WHERE owner_id = ?", user_id)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rooms.into_iter().collect())
+}
+
+async fn owns_room(pool: &SqlitePool, user_id: u32, room_id: i64) -> bool {
+    sqlx::query_scalar!("SELECT room_id FROM room WHERE room_id = ? AND owner_id = ?", room_id, user_id)
+        .fetch_optional(pool)
+        .await
+        .map(|room| room.is_some())
+        .unwrap_or(false)
+}
+
+/// Room of the device an online status change is about.
+async fn device_room(pool: &SqlitePool, event: &DeviceEvent) -> Option<i64> {
+    let device_id = match event {
+        DeviceEvent::Online { device_id } | DeviceEvent::Offline { device_id } => *device_id,
+        _ => return None,
+    };
+
+    sqlx::query_scalar!("SELECT room_id FROM device WHERE device_id = ?", device_id)
+        .fetch_optional(pool)
+        .await
+        .ok()
+        .flatten()
+        .flatten()
+}
+
+async fn send(socket: &mut WebSocket, msg: &LiveOutput) -> anyhow::Result<()> {
+    let msg = serde_json::to_string(msg)?;
+    socket.send(Message::Text(msg)).await?;
+    Ok(())
+}
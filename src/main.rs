@@ -1,13 +1,39 @@
 use dotenvy::dotenv;
 use sqlx::sqlite::SqlitePoolOptions;
 
+mod actuator;
+mod aggregation;
 mod auth;
+mod automation;
+mod calibration;
+mod clock;
+#[cfg(feature = "coap")]
+mod coap;
+mod codec;
+mod device;
+mod diagnostics;
+mod discovery;
 mod esp_websockets;
+mod event_log;
+mod events;
+mod firmware;
+mod health;
+mod heating;
+mod hub;
+mod ingest;
+mod live;
+mod maintenance;
+mod metric;
 mod middleware;
+#[cfg(feature = "mqtt")]
+mod mqtt;
 mod room;
 mod router;
+mod runtime;
 mod utils;
 mod schedule;
+mod telemetry;
+mod window;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -17,13 +43,31 @@ async fn main() -> anyhow::Result<()> {
         .connect(&std::env::var("DATABASE_URL")?)
         .await?;
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
-    let server = axum::serve(listener, router::router(pool));
+    let hub = hub::DeviceHub::from_env();
 
-    println!("Server is listening on port 3000!");
+    tokio::spawn(runtime::run(pool.clone()));
+    tokio::spawn(heating::run(pool.clone(), hub.clone()));
+    #[cfg(feature = "coap")]
+    tokio::spawn(coap::run(pool.clone(), hub.clone()));
+    #[cfg(feature = "mqtt")]
+    tokio::spawn(mqtt::run(pool.clone(), hub.clone()));
+
+    let port: u16 = utils::config::env_or("PORT", 3000);
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
+    let server = axum::serve(listener, router::router(pool, hub));
+
+    let _discovery = match discovery::advertise(port) {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            println!("Failed to advertise via mDNS: {}", e);
+            None
+        }
+    };
+
+    println!("Server is listening on port {}!", port);
     server
         .await
-        .expect("Failed to start server on 0.0.0.0:3000");
+        .unwrap_or_else(|_| panic!("Failed to start server on 0.0.0.0:{}", port));
 
     Ok(())
 }
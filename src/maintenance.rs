@@ -0,0 +1,250 @@
+use crate::events::{self, DeviceEvent};
+use crate::utils::jwt::JWTAuth;
+use axum::extract::Path;
+use axum::http::{HeaderValue, StatusCode};
+use axum::routing::{delete, get, post};
+use axum::{Extension, Json, Router};
+use http::header::{CONTENT_TYPE, COOKIE};
+use http::Method;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tower_http::cors::CorsLayer;
+
+const HOUR_MS: f64 = 3_600_000.0;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CreateMaintenanceDto {
+    actuator_id: i64,
+    name: String,
+    /// Hours of actuator runtime between two services.
+    interval_hours: f64,
+}
+
+/// Recurring task due after a number of hours the actuator has been on,
+/// e.g. changing the filter of a fan.
+#[derive(Serialize, Deserialize, Clone)]
+struct Maintenance {
+    id: i64,
+    actuator_id: i64,
+    name: String,
+    interval_hours: f64,
+    /// Runtime since the last service.
+    runtime_hours: f64,
+    due: bool,
+    serviced_at: Option<String>,
+}
+
+pub fn router(pool: SqlitePool) -> Router {
+    Router::new()
+        .route("/", get(get_maintenance_controller))
+        .route("/", post(create_maintenance_controller))
+        .route("/:id", delete(delete_maintenance_controller))
+        .route("/:id/service", post(mark_serviced_controller))
+        .layer(
+            CorsLayer::new()
+                .allow_origin("http://localhost:5173".parse::<HeaderValue>().unwrap())
+                .allow_credentials(true)
+                .allow_headers([COOKIE, CONTENT_TYPE])
+                .allow_methods([Method::GET, Method::POST, Method::DELETE]),
+        )
+        .layer(Extension(pool))
+}
+
+/// Emits a reminder for every task of the actuator that became due since the
+/// last check. Each task is reminded of once per service interval.
+pub async fn check(pool: &SqlitePool, actuator_id: i64) -> anyhow::Result<()> {
+    let due = sqlx::query!(
+        r#"
+        SELECT m.maintenance_id, m.name, a.device_id, COALESCE(a.room_id, d.room_id) AS "room_id: i64"
+            FROM maintenance m
+            INNER JOIN actuator a ON a.actuator_id = m.actuator_id
+            INNER JOIN device d ON d.device_id = a.device_id
+            WHERE m.actuator_id = ? AND NOT m.notified
+            AND a.total_runtime_ms - m.serviced_runtime_ms >= m.interval_hours * 3600000
+        "#,
+        actuator_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for task in due {
+        sqlx::query!(
+            "UPDATE maintenance SET notified = TRUE WHERE maintenance_id = ?",
+            task.maintenance_id
+        )
+        .execute(pool)
+        .await?;
+
+        events::emit(DeviceEvent::MaintenanceDue {
+            device_id: task.device_id,
+            room_id: task.room_id,
+            actuator_id,
+            maintenance_id: task.maintenance_id,
+            name: task.name,
+        });
+    }
+
+    Ok(())
+}
+
+async fn get_maintenance_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+) -> Result<Json<Vec<Maintenance>>, (StatusCode, String)> {
+    let res = get_maintenance_service(pool, jwt_auth.id, None)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn get_maintenance_service(
+    pool: SqlitePool,
+    user_id: u32,
+    maintenance_id: Option<i64>,
+) -> anyhow::Result<Vec<Maintenance>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT m.maintenance_id, m.actuator_id, m.name, m.interval_hours, m.serviced_at,
+               a.total_runtime_ms - m.serviced_runtime_ms AS "runtime_ms!: i64"
+            FROM maintenance m
+            INNER JOIN actuator a ON a.actuator_id = m.actuator_id
+            WHERE m.owner_id = ?
+            AND (? IS NULL OR m.maintenance_id = ?)
+        "#,
+        user_id,
+        maintenance_id,
+        maintenance_id,
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let mut res = vec![];
+
+    for row in rows {
+        let runtime_hours = row.runtime_ms as f64 / HOUR_MS;
+        res.push(Maintenance {
+            id: row.maintenance_id,
+            actuator_id: row.actuator_id,
+            name: row.name,
+            interval_hours: row.interval_hours,
+            runtime_hours,
+            due: runtime_hours >= row.interval_hours,
+            serviced_at: row.serviced_at.map(|d| d.to_string()),
+        });
+    }
+
+    Ok(res)
+}
+
+async fn create_maintenance_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Json(create_maintenance_dto): Json<CreateMaintenanceDto>,
+) -> Result<Json<Maintenance>, (StatusCode, String)> {
+    let interval_hours = create_maintenance_dto.interval_hours;
+    if !interval_hours.is_finite() || interval_hours <= 0.0 {
+        return Err((StatusCode::BAD_REQUEST, "Interval must be positive".to_string()));
+    }
+
+    let res = create_maintenance_service(pool, jwt_auth.id, create_maintenance_dto)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+/// The count starts at the actuator's current runtime, as if it had just
+/// been serviced.
+async fn create_maintenance_service(
+    pool: SqlitePool,
+    user_id: u32,
+    dto: CreateMaintenanceDto,
+) -> anyhow::Result<Maintenance> {
+    let id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO maintenance(owner_id, actuator_id, name, interval_hours, serviced_runtime_ms)
+            SELECT owner_id, actuator_id, ?, ?, total_runtime_ms
+                FROM actuator WHERE actuator_id = ? AND owner_id = ?
+            RETURNING maintenance_id
+        "#,
+        dto.name,
+        dto.interval_hours,
+        dto.actuator_id,
+        user_id,
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or(anyhow::Error::msg("Actuator not found"))?;
+
+    get_maintenance_service(pool, user_id, Some(id))
+        .await?
+        .pop()
+        .ok_or(anyhow::Error::msg("Maintenance not found"))
+}
+
+async fn delete_maintenance_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Path(id): Path<i64>,
+) -> Result<String, (StatusCode, String)> {
+    sqlx::query!(
+        "DELETE FROM maintenance WHERE owner_id = ? AND maintenance_id = ?",
+        jwt_auth.id,
+        id,
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| {
+        let err = e.to_string();
+        (StatusCode::INTERNAL_SERVER_ERROR, err)
+    })?;
+
+    Ok("Successfully deleted".to_string())
+}
+
+async fn mark_serviced_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Path(id): Path<i64>,
+) -> Result<Json<Maintenance>, (StatusCode, String)> {
+    let res = mark_serviced_service(pool, jwt_auth.id, id)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+/// Marks the task as done, restarting its interval.
+async fn mark_serviced_service(pool: SqlitePool, user_id: u32, maintenance_id: i64) -> anyhow::Result<Maintenance> {
+    let res = sqlx::query!(
+        r#"
+        UPDATE maintenance
+            SET serviced_runtime_ms = (SELECT total_runtime_ms FROM actuator WHERE actuator_id = maintenance.actuator_id),
+            serviced_at = datetime('now'),
+            notified = FALSE
+            WHERE owner_id = ? AND maintenance_id = ?
+        "#,
+        user_id,
+        maintenance_id,
+    )
+    .execute(&pool)
+    .await?;
+    if res.rows_affected() == 0 {
+        return Err(anyhow::Error::msg("Maintenance not found"));
+    }
+
+    get_maintenance_service(pool, user_id, Some(maintenance_id))
+        .await?
+        .pop()
+        .ok_or(anyhow::Error::msg("Maintenance not found"))
+}
@@ -0,0 +1,117 @@
+use crate::utils::config::env_or;
+use crate::utils::jwt::JWTAuth;
+use axum::http::{HeaderValue, StatusCode};
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use http::header::{CONTENT_TYPE, COOKIE};
+use http::Method;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tower_http::cors::CorsLayer;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct NewMetricTypeDto {
+    name: String,
+    unit: String,
+    description: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct MetricType {
+    id: i64,
+    name: String,
+    unit: String,
+    description: String,
+}
+
+pub fn router(pool: SqlitePool) -> Router {
+    Router::new()
+        .route("/", get(get_metric_types_controller))
+        .route("/", post(new_metric_type_controller))
+        .layer(
+            CorsLayer::new()
+                .allow_origin("http://localhost:5173".parse::<HeaderValue>().unwrap())
+                .allow_credentials(true)
+                .allow_headers([COOKIE, CONTENT_TYPE])
+                .allow_methods([Method::GET, Method::POST]),
+        )
+        .layer(Extension(pool))
+}
+
+/// Metric names are matched case-insensitively, e.g. `CO2` is `co2`.
+pub fn normalize_name(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+async fn get_metric_types_controller(
+    Extension(pool): Extension<SqlitePool>,
+    _jwt_auth: JWTAuth,
+) -> Result<Json<Vec<MetricType>>, (StatusCode, String)> {
+    let res = get_metric_types_service(pool).await.map_err(|e| {
+        let err = e.to_string();
+        (StatusCode::INTERNAL_SERVER_ERROR, err)
+    })?;
+
+    Ok(Json(res))
+}
+
+async fn get_metric_types_service(pool: SqlitePool) -> anyhow::Result<Vec<MetricType>> {
+    let rows = sqlx::query!("SELECT * FROM metric_type ORDER BY metric_id")
+        .fetch_all(&pool)
+        .await?;
+
+    let mut res = vec![];
+
+    for row in rows {
+        res.push(MetricType {
+            id: row.metric_id,
+            name: row.name,
+            unit: row.unit,
+            description: row.description,
+        });
+    }
+
+    Ok(res)
+}
+
+/// The registry is shared by every user, so only the users listed in
+/// `METRIC_ADMINS` (comma separated emails) may extend it.
+async fn new_metric_type_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Json(new_metric_type_dto): Json<NewMetricTypeDto>,
+) -> Result<Json<MetricType>, (StatusCode, String)> {
+    let admins = env_or("METRIC_ADMINS", String::new());
+    if !admins.split(',').any(|email| email.trim() == jwt_auth.email) {
+        return Err((StatusCode::FORBIDDEN, "Only administrators can add metric types".to_string()));
+    }
+
+    let res = new_metric_type_service(pool, new_metric_type_dto)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn new_metric_type_service(pool: SqlitePool, dto: NewMetricTypeDto) -> anyhow::Result<MetricType> {
+    let name = normalize_name(&dto.name);
+    let description = dto.description.unwrap_or_default();
+    let res = sqlx::query!(
+        "INSERT INTO metric_type(name, unit, description) VALUES (?, ?, ?) RETURNING *",
+        name,
+        dto.unit,
+        description,
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    Ok(MetricType {
+        id: res.metric_id,
+        name: res.name,
+        unit: res.unit,
+        description: res.description,
+    })
+}
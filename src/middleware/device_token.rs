@@ -0,0 +1,38 @@
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Query};
+use axum::http::request::Parts;
+use http::header::AUTHORIZATION;
+use serde::Deserialize;
+use std::convert::Infallible;
+use crate::utils::device_token::DeviceToken;
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for DeviceToken
+    where
+        S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        _ = state;
+        let bearer = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(|v| v.to_string());
+        if bearer.is_some() {
+            return Ok(DeviceToken(bearer));
+        }
+
+        let query = Query::<TokenQuery>::try_from_uri(&parts.uri)
+            .ok()
+            .and_then(|Query(q)| q.token);
+        Ok(DeviceToken(query))
+    }
+}
@@ -1 +1,2 @@
+pub mod device_token;
 pub mod jwt;
\ No newline at end of file
@@ -0,0 +1,320 @@
+use crate::actuator;
+use crate::clock;
+use crate::esp_websockets::{
+    self, settings, Hello, WsInnerData, WsInputData, WsOutputData, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::events::{self, DeviceEvent};
+use crate::firmware;
+use crate::hub::DeviceHub;
+use crate::ingest::{self, MetricReading};
+use crate::telemetry;
+use crate::utils::config::env_or;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Bridge for hardware that speaks MQTT instead of our WebSocket protocol.
+///
+/// Devices are identified by the first `+` of the subscribed topics, e.g.
+/// `smarty/5/telemetry`. The messages carry no credentials, so they are only
+/// accepted for devices whose owner enabled MQTT for them, and the broker's
+/// ACLs have to keep other clients from publishing on those topics. Two kinds of topics are mapped
+/// onto the ingest path:
+/// - `MQTT_TELEMETRY_TOPIC` carries JSON messages in the same format as the
+///   WebSocket (`{"type": "temp", "data": {...}}`), without the device id.
+///   Devices announce their capabilities with a hello on it, commands that
+///   need a capability they did not announce (e.g. firmware updates) are not
+///   forwarded.
+/// - `MQTT_METRIC_TOPIC` carries a bare number, with the second `+` naming the
+///   metric, as published by Tasmota, Shelly or Zigbee2MQTT style devices.
+///
+/// Commands for the device are published as JSON on `MQTT_COMMAND_TOPIC`,
+/// where `{device_id}` is replaced with the device's id.
+pub async fn run(pool: SqlitePool, hub: DeviceHub) {
+    let host = env_or("MQTT_HOST", "localhost".to_string());
+    let port = env_or("MQTT_PORT", 1883);
+    let telemetry_topic = env_or("MQTT_TELEMETRY_TOPIC", "smarty/+/telemetry".to_string());
+    let metric_topic = env_or("MQTT_METRIC_TOPIC", "smarty/+/metric/+".to_string());
+
+    let mut options = MqttOptions::new(
+        env_or("MQTT_CLIENT_ID", "smarty-backend".to_string()),
+        host.clone(),
+        port,
+    );
+    options.set_keep_alive(Duration::from_secs(30));
+    if let (Ok(username), Ok(password)) =
+        (std::env::var("MQTT_USERNAME"), std::env::var("MQTT_PASSWORD"))
+    {
+        options.set_credentials(username, password);
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+    let mut sessions = HashMap::new();
+    println!("MQTT bridge connecting to {}:{}", host, port);
+
+    loop {
+        let event = match eventloop.poll().await {
+            Ok(event) => event,
+            Err(e) => {
+                println!("MQTT connection failed: {}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        match event {
+            // Subscriptions do not survive a clean session, renew them on
+            // every (re)connect.
+            Event::Incoming(Packet::ConnAck(_)) => {
+                for topic in [&telemetry_topic, &metric_topic] {
+                    if let Err(e) = client.subscribe(topic, QoS::AtLeastOnce).await {
+                        println!("Failed to subscribe to {}: {}", topic, e);
+                    }
+                }
+            }
+            Event::Incoming(Packet::Publish(publish)) => {
+                let input = if let Some(captures) = match_topic(&telemetry_topic, &publish.topic) {
+                    parse_telemetry(&captures, &publish.payload)
+                } else if let Some(captures) = match_topic(&metric_topic, &publish.topic) {
+                    parse_metric(&captures, &publish.payload)
+                } else {
+                    None
+                };
+
+                let Some(input) = input else {
+                    println!("Ignoring MQTT message on {}", publish.topic);
+                    continue;
+                };
+
+                let device_id = input.device_id;
+                if !mqtt_enabled(&pool, device_id).await {
+                    println!("Ignoring MQTT message of device {} without MQTT enabled", device_id);
+                    continue;
+                }
+
+                // Capabilities decide which of the pending commands are
+                // forwarded, store them before registering.
+                let update = match &input.inner {
+                    WsInnerData::Hello(hello) => match save_hello(&pool, device_id, hello).await {
+                        Ok(update) => update,
+                        Err(e) => {
+                            println!("Failed to process hello of client {}: {}", device_id, e);
+                            continue;
+                        }
+                    },
+                    _ => None,
+                };
+
+                register(&hub, &client, &pool, &mut sessions, device_id).await;
+                if let Some(update) = update {
+                    _ = hub.send(device_id, update).await;
+                }
+                let time_request = matches!(input.inner, WsInnerData::TimeRequest);
+                if let Err(e) = ingest::process_input(&pool, &hub, device_id, input).await {
+                    println!("Failed to process message of client {}: {}", device_id, e);
+                }
+                if time_request {
+                    _ = hub.send(device_id, clock::time_message(&pool, device_id).await).await;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Matches `topic` against an MQTT filter, returning what the wildcards
+/// matched.
+fn match_topic<'a>(filter: &str, topic: &'a str) -> Option<Vec<&'a str>> {
+    let mut captures = vec![];
+    let mut levels = topic.split('/');
+
+    for (i, part) in filter.split('/').enumerate() {
+        match part {
+            "#" => {
+                let rest = topic.splitn(i + 1, '/').nth(i).unwrap_or("");
+                captures.push(rest);
+                return Some(captures);
+            }
+            "+" => captures.push(levels.next()?),
+            part => {
+                if levels.next()? != part {
+                    return None;
+                }
+            }
+        }
+    }
+
+    match levels.next() {
+        Some(_) => None,
+        None => Some(captures),
+    }
+}
+
+fn parse_telemetry(captures: &[&str], payload: &[u8]) -> Option<WsInputData> {
+    let device_id = captures.first()?.parse().ok()?;
+    let inner: WsInnerData = serde_json::from_slice(payload).ok()?;
+
+    Some(WsInputData {
+        device_id,
+        seq: None,
+        inner,
+    })
+}
+
+fn parse_metric(captures: &[&str], payload: &[u8]) -> Option<WsInputData> {
+    let device_id = captures.first()?.parse().ok()?;
+    let name = captures.get(1)?.to_lowercase();
+    let value = std::str::from_utf8(payload).ok()?.trim().parse().ok()?;
+
+    Some(WsInputData {
+        device_id,
+        seq: None,
+        inner: WsInnerData::Metric(MetricReading {
+            name,
+            value,
+            unit: None,
+            ts: None,
+        }),
+    })
+}
+
+/// Stores what the device announced about itself, returning the firmware
+/// update it still has to install.
+async fn save_hello(pool: &SqlitePool, device_id: i64, hello: &Hello) -> anyhow::Result<Option<WsOutputData>> {
+    if hello.protocol_version < MIN_PROTOCOL_VERSION {
+        return Err(anyhow::Error::msg(format!(
+            "Protocol version {} is not supported, minimum is {}",
+            hello.protocol_version, MIN_PROTOCOL_VERSION
+        )));
+    }
+
+    let protocol_version = hello.protocol_version.min(PROTOCOL_VERSION);
+    esp_websockets::save_hello(pool, device_id, protocol_version, hello).await?;
+    firmware::on_hello(pool, device_id, &hello.firmware_version).await
+}
+
+async fn mqtt_enabled(pool: &SqlitePool, device_id: i64) -> bool {
+    let enabled = sqlx::query_scalar!("SELECT mqtt_enabled FROM device WHERE device_id = ?", device_id)
+        .fetch_optional(pool)
+        .await;
+
+    matches!(enabled, Ok(Some(true)))
+}
+
+/// Makes the device reachable for commands by forwarding everything sent to it
+/// onto its command topic. Devices with a live WebSocket keep using it. MQTT
+/// has no connection per device, so the device counts as connected until it
+/// has been quiet for `MQTT_SESSION_TIMEOUT_MS`.
+async fn register(
+    hub: &DeviceHub,
+    client: &AsyncClient,
+    pool: &SqlitePool,
+    sessions: &mut HashMap<i64, watch::Sender<Instant>>,
+    device_id: i64,
+) {
+    if let Some(last_seen) = sessions.get(&device_id) {
+        if last_seen.send(Instant::now()).is_ok() {
+            return;
+        }
+        sessions.remove(&device_id);
+    }
+    if hub.is_connected(device_id) {
+        return;
+    }
+    let Ok(mut connection) = hub.register(device_id) else {
+        return;
+    };
+    connection.queue(settings());
+    connection.queue(clock::time_message(pool, device_id).await);
+    for command in actuator::pending_commands(pool, device_id).await.unwrap_or_default() {
+        connection.queue(command);
+    }
+    events::emit(DeviceEvent::Online { device_id });
+
+    let (last_seen, mut seen) = watch::channel(Instant::now());
+    sessions.insert(device_id, last_seen);
+
+    let timeout = Duration::from_millis(env_or("MQTT_SESSION_TIMEOUT_MS", 300000u64).max(1));
+    let topic = env_or("MQTT_COMMAND_TOPIC", "smarty/{device_id}/command".to_string())
+        .replace("{device_id}", &device_id.to_string());
+    let (hub, client, pool) = (hub.clone(), client.clone(), pool.clone());
+    tokio::spawn(async move {
+        loop {
+            let deadline = *seen.borrow_and_update() + timeout;
+            tokio::select! {
+                msg = connection.recv() => {
+                    // Replaced by a WebSocket connection.
+                    let Some(msg) = msg else {
+                        return;
+                    };
+                    if let Some(capability) = msg.required_capability() {
+                        if !telemetry::capabilities(&pool, device_id).await.contains(capability) {
+                            continue;
+                        }
+                    }
+
+                    let payload = match serde_json::to_vec(&msg) {
+                        Ok(payload) => payload,
+                        Err(_) => continue,
+                    };
+                    if let Err(e) = client.publish(&topic, QoS::AtLeastOnce, false, payload).await {
+                        println!("Failed to publish command to {}: {}", topic, e);
+                    }
+                }
+                changed = seen.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline) => break,
+            }
+        }
+
+        println!("MQTT client {} timed out", device_id);
+        if hub.unregister(device_id, connection.generation) {
+            events::emit(DeviceEvent::Offline { device_id });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_single_level_wildcards() {
+        assert_eq!(match_topic("smarty/+/telemetry", "smarty/5/telemetry"), Some(vec!["5"]));
+        assert_eq!(
+            match_topic("smarty/+/metric/+", "smarty/5/metric/co2"),
+            Some(vec!["5", "co2"])
+        );
+        assert_eq!(match_topic("smarty/+/telemetry", "smarty/5/6/telemetry"), None);
+        assert_eq!(match_topic("smarty/+/telemetry", "smarty/5"), None);
+        assert_eq!(match_topic("smarty/+/telemetry", "other/5/telemetry"), None);
+    }
+
+    #[test]
+    fn matches_exact_levels_only() {
+        assert_eq!(match_topic("smarty/telemetry", "smarty/telemetry"), Some(vec![]));
+        assert_eq!(match_topic("smarty/telemetry", "smarty/telemetry/5"), None);
+        assert_eq!(match_topic("smarty/+", "smarty/"), Some(vec![""]));
+    }
+
+    #[test]
+    fn matches_multi_level_wildcard() {
+        assert_eq!(match_topic("zigbee/+/#", "zigbee/5/sensor/co2"), Some(vec!["5", "sensor/co2"]));
+        assert_eq!(match_topic("zigbee/+/#", "zigbee/5"), Some(vec!["5", ""]));
+    }
+
+    #[test]
+    fn parses_metric_payload() {
+        let input = parse_metric(&["5", "CO2"], b" 612.5\n").unwrap();
+        assert_eq!(input.device_id, 5);
+        assert!(matches!(input.inner, WsInnerData::Metric(m) if m.name == "co2" && m.value == 612.5));
+        assert!(parse_metric(&["5", "co2"], b"on").is_none());
+        assert!(parse_metric(&["five", "co2"], b"1").is_none());
+    }
+}
@@ -5,15 +5,19 @@ use axum::routing::{get, patch, post, delete};
 use axum::{Extension, Json, Router};
 use http::header::{CONTENT_TYPE, COOKIE};
 use http::Method;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::json;
 use sqlx::{Executor, SqlitePool};
+use std::fmt;
 use tower_http::cors::CorsLayer;
+use crate::actuator::{self, Actuator, ActuatorFilter};
+use crate::aggregation::Aggregation;
+use crate::heating;
 use crate::utils::Pagination;
 
 #[derive(Serialize, Deserialize, Clone)]
 struct CreateRoomDto {
-    device_id: i64,
+    device_id: Option<i64>,
     icon_id: u32,
     name: String,
 }
@@ -22,15 +26,39 @@ struct CreateRoomDto {
 struct UpdateRoomDto {
     id: i64,
     name: Option<String>,
-    device_id: Option<u64>,
+    device_id: Option<i64>,
     icon_id: Option<u32>,
+    aggregation: Option<Aggregation>,
+    /// `null` clears it, the device has to be in the room otherwise.
+    #[serde(default, deserialize_with = "nullable")]
+    primary_device_id: Option<Option<i64>>,
+    pause_heating_on_contact: Option<bool>,
+    /// 0 disables open window detection.
+    window_open_pause_minutes: Option<u32>,
 }
 
+/// The room does not exist or belongs to someone else.
+#[derive(Debug)]
+struct RoomNotFound;
+
+impl fmt::Display for RoomNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Room not found")
+    }
+}
+
+impl std::error::Error for RoomNotFound {}
+
 #[derive(Serialize, Deserialize, Clone)]
 struct GetRoom {
     id: i64,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+struct HistoryFilter {
+    device_id: Option<i64>,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 struct Room {
     id: i64,
@@ -40,10 +68,29 @@ struct Room {
     humidity: f64,
     watthour: f64,
     lastpresence: i64,
+    aggregation: String,
+    primary_device_id: Option<i64>,
+    health: String,
+    pause_heating_on_contact: bool,
+    /// Why heating is paused, empty if it is not.
+    heating_paused_by: Vec<String>,
+    /// Set while a sharp temperature drop suggests an open window.
+    window_open: bool,
+    window_open_pause_minutes: u32,
+    actuators: Vec<Actuator>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct MetricSample {
+    device_id: Option<i64>,
+    value: f64,
+    unit: String,
+    created_at: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 struct RoomHistory {
+    device_id: Option<i64>,
     temperature: f64,
     humidity: f64,
     watthour: f64,
@@ -57,6 +104,7 @@ pub fn router(pool: SqlitePool) -> Router {
         .route("/", patch(update_room_controller))
         .route("/:id", delete(delete_room_controller))
         .route("/history", get(get_rooms_history_controller))
+        .route("/history/:metric", get(get_metric_history_controller))
         .layer(
             CorsLayer::new()
                 .allow_origin("http://localhost:5173".parse::<HeaderValue>().unwrap())
@@ -75,8 +123,12 @@ async fn update_room_controller(
     update_room_service(pool, jwt_auth.id, update_room_dto)
         .await
         .map_err(|e| {
-            let err = e.to_string();
-            (StatusCode::INTERNAL_SERVER_ERROR, err)
+            let status = if e.is::<RoomNotFound>() {
+                StatusCode::NOT_FOUND
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            (status, e.to_string())
         })?;
 
     Ok("Successfully updated".to_string())
@@ -87,22 +139,75 @@ async fn update_room_service(
     user_id: u32,
     update_dto: UpdateRoomDto,
 ) -> anyhow::Result<()> {
+    let aggregation = update_dto.aggregation.map(|a| a.as_str());
+    let mut tx = pool.begin().await?;
     let query = sqlx::query!(
         r#"
         UPDATE room
             SET room_name = COALESCE(?, room_name),
-            icon_id = COALESCE(?, icon_id)
+            icon_id = COALESCE(?, icon_id),
+            aggregation = COALESCE(?, aggregation),
+            pause_heating_on_contact = COALESCE(?, pause_heating_on_contact),
+            window_open_pause_minutes = COALESCE(?, window_open_pause_minutes)
             WHERE owner_id = ? AND room_id = ?
         "#,
         update_dto.name,
         update_dto.icon_id,
+        aggregation,
+        update_dto.pause_heating_on_contact,
+        update_dto.window_open_pause_minutes,
         user_id,
         update_dto.id
     );
-    pool.execute(query).await?;
+    if tx.execute(query).await?.rows_affected() == 0 {
+        return Err(RoomNotFound.into());
+    }
+
+    // Re-binding replaces whatever devices were reporting into the room.
+    if let Some(device_id) = update_dto.device_id {
+        sqlx::query!(
+            "UPDATE device SET room_id = NULL WHERE room_id = ? AND owner_id = ? AND device_id != ?",
+            update_dto.id,
+            user_id,
+            device_id,
+        )
+            .execute(&mut *tx)
+            .await?;
+        crate::device::bind_device(&mut tx, user_id, device_id, update_dto.id).await?;
+    }
+
+    if let Some(primary_device_id) = update_dto.primary_device_id {
+        if let Some(device_id) = primary_device_id {
+            sqlx::query!(
+                "SELECT device_id FROM device WHERE device_id = ? AND owner_id = ? AND room_id = ?",
+                device_id,
+                user_id,
+                update_dto.id,
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(anyhow::Error::msg("Primary device is not in the room"))?;
+        }
+        sqlx::query!(
+            "UPDATE room SET primary_device_id = ? WHERE owner_id = ? AND room_id = ?",
+            primary_device_id,
+            user_id,
+            update_dto.id,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    crate::aggregation::refresh_room_current(&mut tx, update_dto.id).await?;
+
+    tx.commit().await?;
     Ok(())
 }
 
+/// Tells an explicit `null` (`Some(None)`) apart from a missing field (`None`).
+fn nullable<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Option<i64>>, D::Error> {
+    Option::deserialize(deserializer).map(Some)
+}
+
 async fn delete_room_controller(
     Extension(pool): Extension<SqlitePool>,
     jwt_auth: JWTAuth,
@@ -151,16 +256,21 @@ async fn create_room_service(
     user_id: u32,
     create_room: CreateRoomDto,
 ) -> anyhow::Result<Room> {
+    let mut tx = pool.begin().await?;
     let res = sqlx::query!(
-        "INSERT INTO room(room_id, owner_id, room_name, icon_id) VALUES (?, ?, ?, ?) RETURNING *",
-        create_room.device_id,
+        "INSERT INTO room(owner_id, room_name, icon_id) VALUES (?, ?, ?) RETURNING *",
         user_id,
         create_room.name,
         create_room.icon_id,
     )
-        .fetch_one(&pool)
+        .fetch_one(&mut *tx)
         .await?;
 
+    if let Some(device_id) = create_room.device_id {
+        crate::device::bind_device(&mut tx, user_id, device_id, res.room_id).await?;
+    }
+    tx.commit().await?;
+
     Ok(Room {
         id: res.room_id,
         name: res.room_name,
@@ -169,6 +279,14 @@ async fn create_room_service(
         humidity: res.current_humidity,
         watthour: res.current_watthour,
         lastpresence: res.last_presence,
+        aggregation: res.aggregation,
+        primary_device_id: res.primary_device_id,
+        health: res.health,
+        pause_heating_on_contact: res.pause_heating_on_contact,
+        heating_paused_by: vec![],
+        window_open: res.window_open_at.is_some(),
+        window_open_pause_minutes: res.window_open_pause_minutes as u32,
+        actuators: vec![],
     })
 }
 
@@ -204,6 +322,12 @@ async fn get_room_service(pool: SqlitePool, room_id: i64, user_id: u32) -> anyho
     )
         .fetch_one(&pool)
         .await?;
+    let filter = ActuatorFilter {
+        room_id: Some(room_id),
+        device_id: None,
+    };
+    let actuators = actuator::list_actuators(&pool, user_id, filter).await?;
+    let mut paused_by = heating::pause_reasons(&pool, user_id).await?;
 
     Ok(Room {
         id: res.room_id,
@@ -213,6 +337,14 @@ async fn get_room_service(pool: SqlitePool, room_id: i64, user_id: u32) -> anyho
         humidity: res.current_humidity,
         watthour: res.current_watthour,
         lastpresence: res.last_presence,
+        aggregation: res.aggregation,
+        primary_device_id: res.primary_device_id,
+        health: res.health,
+        pause_heating_on_contact: res.pause_heating_on_contact,
+        heating_paused_by: paused_by.remove(&room_id).unwrap_or_default(),
+        window_open: res.window_open_at.is_some(),
+        window_open_pause_minutes: res.window_open_pause_minutes as u32,
+        actuators,
     })
 }
 
@@ -220,10 +352,17 @@ async fn get_all_rooms_service(pool: SqlitePool, user_id: u32) -> anyhow::Result
     let rows = sqlx::query!("SELECT * FROM room WHERE owner_id=?", user_id)
         .fetch_all(&pool)
         .await?;
+    let actuators = actuator::list_actuators(&pool, user_id, ActuatorFilter::default()).await?;
+    let mut paused_by = heating::pause_reasons(&pool, user_id).await?;
 
     let mut res = vec![];
 
     for row in rows {
+        let room_actuators = actuators
+            .iter()
+            .filter(|a| a.room_id == Some(row.room_id))
+            .cloned()
+            .collect();
         res.push(Room {
             id: row.room_id,
             name: row.room_name,
@@ -232,6 +371,14 @@ async fn get_all_rooms_service(pool: SqlitePool, user_id: u32) -> anyhow::Result
             humidity: row.current_humidity,
             watthour: row.current_watthour,
             lastpresence: row.last_presence,
+            aggregation: row.aggregation,
+            primary_device_id: row.primary_device_id,
+            health: row.health,
+            pause_heating_on_contact: row.pause_heating_on_contact,
+            heating_paused_by: paused_by.remove(&row.room_id).unwrap_or_default(),
+            window_open: row.window_open_at.is_some(),
+            window_open_pause_minutes: row.window_open_pause_minutes as u32,
+            actuators: room_actuators,
         });
     }
 
@@ -242,9 +389,10 @@ async fn get_rooms_history_controller(
     Extension(pool): Extension<SqlitePool>,
     jwt_auth: JWTAuth,
     Query(GetRoom { id: room_id }): Query<GetRoom>,
+    Query(filter): Query<HistoryFilter>,
     Query(pagination): Query<Pagination>,
 ) -> Result<Json<Vec<RoomHistory>>, (StatusCode, String)> {
-    let res = get_rooms_history_service(pool, jwt_auth.id, room_id, pagination).await.map_err(|e| {
+    let res = get_rooms_history_service(pool, jwt_auth.id, room_id, filter, pagination).await.map_err(|e| {
         let err = e.to_string();
         (StatusCode::INTERNAL_SERVER_ERROR, err)
     })?;
@@ -252,17 +400,20 @@ async fn get_rooms_history_controller(
     Ok(Json(res))
 }
 
-async fn get_rooms_history_service(pool: SqlitePool, user_id: u32, room_id: i64, pagination: Pagination) -> anyhow::Result<Vec<RoomHistory>> {
+async fn get_rooms_history_service(pool: SqlitePool, user_id: u32, room_id: i64, filter: HistoryFilter, pagination: Pagination) -> anyhow::Result<Vec<RoomHistory>> {
     let rows = sqlx::query!(r#"
-        SELECT temperature, humidity, watthour, created_at
+        SELECT room_history.device_id, temperature, humidity, watthour, created_at
         FROM room_history
             INNER JOIN room r on r.room_id = room_history.room_id
             WHERE r.owner_id = ? AND r.room_id = ?
+            AND (? IS NULL OR room_history.device_id = ?)
             LIMIT ?
             OFFSET ?
     "#,
         user_id,
         room_id,
+        filter.device_id,
+        filter.device_id,
         pagination.take,
         pagination.skip,
     )
@@ -272,6 +423,7 @@ async fn get_rooms_history_service(pool: SqlitePool, user_id: u32, room_id: i64,
 
     for row in rows {
         result.push(RoomHistory {
+            device_id: row.device_id,
             temperature: row.temperature,
             humidity: row.humidity,
             watthour: row.watthour,
@@ -281,3 +433,56 @@ async fn get_rooms_history_service(pool: SqlitePool, user_id: u32, room_id: i64,
 
     Ok(result)
 }
+
+async fn get_metric_history_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Path(metric): Path<String>,
+    Query(GetRoom { id: room_id }): Query<GetRoom>,
+    Query(filter): Query<HistoryFilter>,
+    Query(pagination): Query<Pagination>,
+) -> Result<Json<Vec<MetricSample>>, (StatusCode, String)> {
+    let res = get_metric_history_service(pool, jwt_auth.id, room_id, metric, filter, pagination).await.map_err(|e| {
+        let err = e.to_string();
+        (StatusCode::INTERNAL_SERVER_ERROR, err)
+    })?;
+
+    Ok(Json(res))
+}
+
+async fn get_metric_history_service(pool: SqlitePool, user_id: u32, room_id: i64, metric: String, filter: HistoryFilter, pagination: Pagination) -> anyhow::Result<Vec<MetricSample>> {
+    let metric = crate::metric::normalize_name(&metric);
+    let rows = sqlx::query!(r#"
+        SELECT s.device_id, s.value, m.unit, s.created_at
+        FROM metric_sample s
+            INNER JOIN metric_type m on m.metric_id = s.metric_id
+            INNER JOIN room r on r.room_id = s.room_id
+            WHERE r.owner_id = ? AND r.room_id = ? AND m.name = ?
+            AND (? IS NULL OR s.device_id = ?)
+            ORDER BY s.created_at
+            LIMIT ?
+            OFFSET ?
+    "#,
+        user_id,
+        room_id,
+        metric,
+        filter.device_id,
+        filter.device_id,
+        pagination.take,
+        pagination.skip,
+    )
+        .fetch_all(&pool).await?;
+
+    let mut result = vec![];
+
+    for row in rows {
+        result.push(MetricSample {
+            device_id: row.device_id,
+            value: row.value,
+            unit: row.unit,
+            created_at: row.created_at.to_string(),
+        });
+    }
+
+    Ok(result)
+}
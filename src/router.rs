@@ -1,3 +1,4 @@
+use crate::hub::DeviceHub;
 use axum::http::HeaderValue;
 use axum::{routing::get, Extension, Router};
 use http::header::{CONTENT_TYPE, COOKIE};
@@ -5,21 +6,35 @@ use http::Method;
 use sqlx::SqlitePool;
 use tower_http::cors::CorsLayer;
 
-pub fn router(conn: SqlitePool) -> Router {
+/// Origin of the web app, the only site allowed to use the API from a browser.
+pub const FRONTEND_ORIGIN: &str = "http://localhost:5173";
+
+pub fn router(conn: SqlitePool, hub: DeviceHub) -> Router {
     Router::new()
+        .nest_service("/actuator", crate::actuator::router(conn.clone()))
         .nest_service("/room", crate::room::router(conn.clone()))
         .nest_service("/auth", crate::auth::router(conn.clone()))
+        .nest_service("/automation", crate::automation::router(conn.clone()))
+        .nest_service("/calibration", crate::calibration::router(conn.clone()))
+        .nest_service("/device", crate::device::router(conn.clone()))
+        .nest_service("/event", crate::event_log::router(conn.clone()))
+        .nest_service("/firmware", crate::firmware::router(conn.clone()))
+        .nest_service("/maintenance", crate::maintenance::router(conn.clone()))
+        .nest_service("/metric", crate::metric::router(conn.clone()))
+        .nest_service("/runtime", crate::runtime::router(conn.clone()))
         .nest_service("/schedule", crate::schedule::router(conn.clone()))
         .route(
             "/ws/:device_id",
             get(crate::esp_websockets::websocket_handler),
         )
+        .route("/live", get(crate::live::live_handler))
         .layer(
             CorsLayer::new()
-                .allow_origin("http://localhost:5173".parse::<HeaderValue>().unwrap())
+                .allow_origin(FRONTEND_ORIGIN.parse::<HeaderValue>().unwrap())
                 .allow_credentials(true)
                 .allow_headers([COOKIE, CONTENT_TYPE])
-                .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE]),
+                .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::PUT, Method::DELETE]),
         )
         .layer(Extension(conn))
+        .layer(Extension(hub))
 }
@@ -0,0 +1,634 @@
+use crate::actuator::{self, ActuatorFilter};
+use crate::clock;
+use crate::ingest::now_ms;
+use crate::maintenance;
+use crate::utils::config::env_or;
+use crate::utils::jwt::JWTAuth;
+use crate::utils::Pagination;
+use axum::extract::Query;
+use axum::http::{HeaderValue, StatusCode};
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use http::header::{CONTENT_TYPE, COOKIE};
+use http::Method;
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+use std::collections::HashMap;
+use std::time::Duration;
+use tower_http::cors::CorsLayer;
+
+const DAY_MS: i64 = 86_400_000;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct RuntimeFilter {
+    room_id: Option<i64>,
+    actuator_id: Option<i64>,
+    /// Number of days up to and including today, 7 by default.
+    days: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct TransitionFilter {
+    actuator_id: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct DailyRuntime {
+    day: String,
+    runtime_ms: i64,
+    /// Share of the day the actuator was on, today counts up to now.
+    duty_cycle: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ActuatorRuntime {
+    actuator_id: i64,
+    room_id: Option<i64>,
+    runtime_ms: i64,
+    duty_cycle: f64,
+    /// Runtime since the actuator was added.
+    total_runtime_ms: i64,
+    days: Vec<DailyRuntime>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct RoomRuntime {
+    room_id: i64,
+    runtime_ms: i64,
+    /// Averaged over the actuators in the room.
+    duty_cycle: f64,
+    days: Vec<DailyRuntime>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Transition {
+    actuator_id: i64,
+    room_id: Option<i64>,
+    is_on: bool,
+    created_at: String,
+}
+
+pub fn router(pool: SqlitePool) -> Router {
+    Router::new()
+        .route("/", get(get_actuator_runtime_controller))
+        .route("/rooms", get(get_room_runtime_controller))
+        .route("/transitions", get(get_transitions_controller))
+        .layer(
+            CorsLayer::new()
+                .allow_origin("http://localhost:5173".parse::<HeaderValue>().unwrap())
+                .allow_credentials(true)
+                .allow_headers([COOKIE, CONTENT_TYPE])
+                .allow_methods([Method::GET]),
+        )
+        .layer(Extension(pool))
+}
+
+/// Periodically books the runtime of actuators that are still on, so daily
+/// totals and maintenance reminders do not wait for them to switch off.
+pub async fn run(pool: SqlitePool) {
+    let interval = Duration::from_millis(env_or("RUNTIME_ACCRUE_INTERVAL_MS", 60000u64).max(1));
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = accrue_running(&pool).await {
+            println!("Failed to account actuator runtime: {}", e);
+        }
+    }
+}
+
+/// Records an on/off transition when a reported state switches the actuator.
+/// Any value above 0 counts as on. Returns the actuator if runtime was booked,
+/// so its maintenance can be checked once the transaction is committed.
+pub async fn record_state(
+    conn: &mut SqliteConnection,
+    device_id: i64,
+    channel: u32,
+    value: f64,
+) -> anyhow::Result<Option<i64>> {
+    let offset = clock::utc_offset_minutes(&mut *conn, device_id).await;
+    let actuator = sqlx::query!(
+        r#"
+        SELECT a.actuator_id AS "actuator_id!", a.on_since_ms, COALESCE(a.room_id, d.room_id) AS "room_id: i64"
+            FROM actuator a
+            INNER JOIN device d ON d.device_id = a.device_id
+            WHERE a.device_id = ? AND a.channel = ?
+        "#,
+        device_id,
+        channel,
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    let Some(actuator) = actuator else {
+        return Ok(None);
+    };
+
+    let is_on = value > 0.0;
+    if is_on == actuator.on_since_ms.is_some() {
+        return Ok(None);
+    }
+
+    let now = now_ms();
+    let next = is_on.then_some(now);
+    if !advance(conn, actuator.actuator_id, actuator.on_since_ms, next).await? {
+        return Ok(None);
+    }
+    if let Some(on_since) = actuator.on_since_ms {
+        accrue(conn, actuator.actuator_id, actuator.room_id, on_since, now, offset).await?;
+    }
+    record_transition(conn, actuator.actuator_id, actuator.room_id, is_on).await?;
+
+    Ok(Some(actuator.actuator_id))
+}
+
+/// Books the runtime of every actuator that is on up to now. The reported state
+/// of a device that has not been heard of for `ACTUATOR_STATE_MAX_AGE_MS` is
+/// unknown, so its clock stops when the device was last seen, as if the
+/// actuator had been switched off then, and starts again once the device
+/// reports it on.
+async fn accrue_running(pool: &SqlitePool) -> anyhow::Result<()> {
+    let max_age = env_or("ACTUATOR_STATE_MAX_AGE_MS", 300000i64);
+    let running = sqlx::query!(
+        r#"
+        SELECT a.actuator_id AS "actuator_id!", a.device_id, a.on_since_ms AS "on_since_ms!",
+               COALESCE(a.room_id, d.room_id) AS "room_id: i64", d.last_seen_ms
+            FROM actuator a
+            INNER JOIN device d ON d.device_id = a.device_id
+            WHERE a.on_since_ms IS NOT NULL
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for actuator in running {
+        let now = now_ms();
+        let offset = clock::utc_offset_minutes(pool, actuator.device_id).await;
+        let last_seen = actuator.last_seen_ms.unwrap_or(actuator.on_since_ms);
+        let alive = now - last_seen <= max_age;
+        let mut tx = pool.begin().await?;
+        let next = alive.then_some(now);
+        if !advance(&mut tx, actuator.actuator_id, Some(actuator.on_since_ms), next).await? {
+            continue;
+        }
+        // Earlier accruals may already have booked past the last sighting.
+        let until = if alive { now } else { last_seen.max(actuator.on_since_ms) };
+        accrue(&mut tx, actuator.actuator_id, actuator.room_id, actuator.on_since_ms, until, offset).await?;
+        if !alive {
+            record_transition(&mut tx, actuator.actuator_id, actuator.room_id, false).await?;
+        }
+        tx.commit().await?;
+
+        maintenance::check(pool, actuator.actuator_id).await?;
+    }
+
+    Ok(())
+}
+
+/// Moves the start of the actuator's running period from `from` to `to`.
+/// Returns `false` if a concurrent report or accrual changed it first, in
+/// which case the period has already been booked.
+async fn advance(
+    conn: &mut SqliteConnection,
+    actuator_id: i64,
+    from: Option<i64>,
+    to: Option<i64>,
+) -> anyhow::Result<bool> {
+    let res = sqlx::query!(
+        "UPDATE actuator SET on_since_ms = ? WHERE actuator_id = ? AND on_since_ms IS ?",
+        to,
+        actuator_id,
+        from,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(res.rows_affected() > 0)
+}
+
+async fn record_transition(
+    conn: &mut SqliteConnection,
+    actuator_id: i64,
+    room_id: Option<i64>,
+    is_on: bool,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        "INSERT INTO actuator_transition(actuator_id, room_id, is_on, created_at) VALUES (?, ?, ?, datetime('now'))",
+        actuator_id,
+        room_id,
+        is_on,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Adds the time between `from_ms` and `to_ms` to the actuator's runtime,
+/// split at local midnight.
+async fn accrue(
+    conn: &mut SqliteConnection,
+    actuator_id: i64,
+    room_id: Option<i64>,
+    from_ms: i64,
+    to_ms: i64,
+    utc_offset_minutes: i32,
+) -> anyhow::Result<()> {
+    for (day, runtime_ms) in split_days(from_ms, to_ms, utc_offset_minutes as i64 * 60000) {
+        let day_start = day * 86400;
+        sqlx::query!(
+            r#"
+            INSERT INTO actuator_runtime(actuator_id, day, room_id, runtime_ms)
+            VALUES (?, date(?, 'unixepoch'), ?, ?)
+            ON CONFLICT(actuator_id, day) DO UPDATE SET
+                runtime_ms = runtime_ms + excluded.runtime_ms,
+                room_id = excluded.room_id
+            "#,
+            actuator_id,
+            day_start,
+            room_id,
+            runtime_ms,
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    let runtime_ms = (to_ms - from_ms).max(0);
+    sqlx::query!(
+        "UPDATE actuator SET total_runtime_ms = total_runtime_ms + ? WHERE actuator_id = ?",
+        runtime_ms,
+        actuator_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Splits the time between `from_ms` and `to_ms` into local days, as pairs of
+/// day index and milliseconds.
+fn split_days(from_ms: i64, to_ms: i64, offset_ms: i64) -> Vec<(i64, i64)> {
+    let mut res = vec![];
+    let mut start = from_ms;
+    while start < to_ms {
+        let day = (start + offset_ms).div_euclid(DAY_MS);
+        let end = to_ms.min((day + 1) * DAY_MS - offset_ms);
+        res.push((day, end - start));
+        start = end;
+    }
+
+    res
+}
+
+/// Local days covered by a report, ending today.
+struct Period {
+    first_day: i64,
+    today: i64,
+    /// Local time, as milliseconds since the epoch.
+    local_ms: i64,
+    offset_ms: i64,
+}
+
+impl Period {
+    async fn new(pool: &SqlitePool, user_id: u32, days: Option<u32>) -> Self {
+        let offset_ms = clock::user_utc_offset_minutes(pool, user_id).await as i64 * 60000;
+        let local_ms = now_ms() + offset_ms;
+        let today = local_ms.div_euclid(DAY_MS);
+        let days = days.unwrap_or(7).max(1) as i64;
+
+        Period {
+            first_day: today - days + 1,
+            today,
+            local_ms,
+            offset_ms,
+        }
+    }
+
+    /// Length of the day in milliseconds, today only counts up to now.
+    fn day_ms(&self, day: i64) -> i64 {
+        if day == self.today {
+            (self.local_ms - day * DAY_MS).max(1)
+        } else {
+            DAY_MS
+        }
+    }
+
+    fn length_ms(&self) -> i64 {
+        (self.first_day..=self.today).map(|day| self.day_ms(day)).sum()
+    }
+}
+
+struct RuntimeRow {
+    actuator_id: i64,
+    room_id: Option<i64>,
+    day: String,
+    day_index: i64,
+    runtime_ms: i64,
+}
+
+async fn fetch_runtime(
+    pool: &SqlitePool,
+    user_id: u32,
+    filter: &RuntimeFilter,
+    period: &Period,
+) -> anyhow::Result<Vec<RuntimeRow>> {
+    let first_day = period.first_day * 86400;
+    let rows = sqlx::query!(
+        r#"
+        SELECT r.actuator_id, r.room_id, r.day AS "day: String",
+               CAST(strftime('%s', r.day) AS INTEGER) / 86400 AS "day_index!: i64", r.runtime_ms
+            FROM actuator_runtime r
+            INNER JOIN actuator a ON a.actuator_id = r.actuator_id
+            WHERE a.owner_id = ?
+            AND (? IS NULL OR r.room_id = ?)
+            AND (? IS NULL OR r.actuator_id = ?)
+            AND r.day >= date(?, 'unixepoch')
+            ORDER BY r.day
+        "#,
+        user_id,
+        filter.room_id,
+        filter.room_id,
+        filter.actuator_id,
+        filter.actuator_id,
+        first_day,
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut res: Vec<RuntimeRow> = rows
+        .into_iter()
+        .map(|row| RuntimeRow {
+            actuator_id: row.actuator_id,
+            room_id: row.room_id,
+            day: row.day,
+            day_index: row.day_index,
+            runtime_ms: row.runtime_ms,
+        })
+        .collect();
+
+    // Actuators that are on have only been booked up to the last accrual.
+    let running = sqlx::query!(
+        r#"
+        SELECT a.actuator_id AS "actuator_id!", a.on_since_ms AS "on_since_ms!",
+               COALESCE(a.room_id, d.room_id) AS "room_id: i64"
+            FROM actuator a
+            INNER JOIN device d ON d.device_id = a.device_id
+            WHERE a.owner_id = ? AND a.on_since_ms IS NOT NULL
+            AND (? IS NULL OR COALESCE(a.room_id, d.room_id) = ?)
+            AND (? IS NULL OR a.actuator_id = ?)
+        "#,
+        user_id,
+        filter.room_id,
+        filter.room_id,
+        filter.actuator_id,
+        filter.actuator_id,
+    )
+    .fetch_all(pool)
+    .await?;
+    let now = now_ms();
+    for actuator in running {
+        for (day_index, runtime_ms) in split_days(actuator.on_since_ms, now, period.offset_ms) {
+            if day_index < period.first_day {
+                continue;
+            }
+            let day_start = day_index * 86400;
+            let day = sqlx::query_scalar!(r#"SELECT date(?, 'unixepoch') AS "day!: String""#, day_start)
+                .fetch_one(pool)
+                .await?;
+            res.push(RuntimeRow {
+                actuator_id: actuator.actuator_id,
+                room_id: actuator.room_id,
+                day,
+                day_index,
+                runtime_ms,
+            });
+        }
+    }
+
+    Ok(res)
+}
+
+/// Sums the rows per day. `count` is how many actuators share each day.
+fn daily(rows: &[&RuntimeRow], period: &Period, count: usize) -> Vec<DailyRuntime> {
+    let mut days: Vec<DailyRuntime> = vec![];
+    for row in rows {
+        let day_ms = (period.day_ms(row.day_index) * count.max(1) as i64) as f64;
+        match days.iter_mut().find(|d| d.day == row.day) {
+            Some(day) => {
+                day.runtime_ms += row.runtime_ms;
+                day.duty_cycle = day.runtime_ms as f64 / day_ms;
+            }
+            None => days.push(DailyRuntime {
+                day: row.day.clone(),
+                runtime_ms: row.runtime_ms,
+                duty_cycle: row.runtime_ms as f64 / day_ms,
+            }),
+        }
+    }
+
+    days
+}
+
+async fn get_actuator_runtime_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Query(filter): Query<RuntimeFilter>,
+) -> Result<Json<Vec<ActuatorRuntime>>, (StatusCode, String)> {
+    let res = get_actuator_runtime_service(pool, jwt_auth.id, filter)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn get_actuator_runtime_service(
+    pool: SqlitePool,
+    user_id: u32,
+    filter: RuntimeFilter,
+) -> anyhow::Result<Vec<ActuatorRuntime>> {
+    let period = Period::new(&pool, user_id, filter.days).await;
+    let rows = fetch_runtime(&pool, user_id, &filter, &period).await?;
+    let now = now_ms();
+    let totals: HashMap<i64, i64> = sqlx::query!(
+        r#"
+        SELECT actuator_id, total_runtime_ms + COALESCE(? - on_since_ms, 0) AS "total_runtime_ms!: i64"
+            FROM actuator
+            WHERE owner_id = ?
+        "#,
+        now,
+        user_id
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|row| (row.actuator_id, row.total_runtime_ms))
+    .collect();
+
+    let actuator_filter = ActuatorFilter {
+        room_id: filter.room_id,
+        device_id: None,
+    };
+    let mut res = vec![];
+
+    for actuator in actuator::list_actuators(&pool, user_id, actuator_filter).await? {
+        if filter.actuator_id.is_some_and(|id| id != actuator.id) {
+            continue;
+        }
+        let rows: Vec<&RuntimeRow> = rows.iter().filter(|r| r.actuator_id == actuator.id).collect();
+        let runtime_ms = rows.iter().map(|r| r.runtime_ms).sum();
+        res.push(ActuatorRuntime {
+            actuator_id: actuator.id,
+            room_id: actuator.room_id,
+            runtime_ms,
+            duty_cycle: runtime_ms as f64 / period.length_ms() as f64,
+            total_runtime_ms: totals.get(&actuator.id).copied().unwrap_or_default(),
+            days: daily(&rows, &period, 1),
+        });
+    }
+
+    Ok(res)
+}
+
+async fn get_room_runtime_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Query(filter): Query<RuntimeFilter>,
+) -> Result<Json<Vec<RoomRuntime>>, (StatusCode, String)> {
+    let res = get_room_runtime_service(pool, jwt_auth.id, filter)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn get_room_runtime_service(
+    pool: SqlitePool,
+    user_id: u32,
+    filter: RuntimeFilter,
+) -> anyhow::Result<Vec<RoomRuntime>> {
+    let period = Period::new(&pool, user_id, filter.days).await;
+    let rows = fetch_runtime(&pool, user_id, &filter, &period).await?;
+    let actuators = actuator::list_actuators(&pool, user_id, ActuatorFilter::default()).await?;
+    let room_ids = sqlx::query_scalar!(
+        "SELECT room_id FROM room WHERE owner_id = ? AND (? IS NULL OR room_id = ?)",
+        user_id,
+        filter.room_id,
+        filter.room_id,
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let mut res = vec![];
+
+    for room_id in room_ids {
+        let count = actuators.iter().filter(|a| a.room_id == Some(room_id)).count();
+        let rows: Vec<&RuntimeRow> = rows.iter().filter(|r| r.room_id == Some(room_id)).collect();
+        let runtime_ms: i64 = rows.iter().map(|r| r.runtime_ms).sum();
+        res.push(RoomRuntime {
+            room_id,
+            runtime_ms,
+            duty_cycle: runtime_ms as f64 / (period.length_ms() * count.max(1) as i64) as f64,
+            days: daily(&rows, &period, count),
+        });
+    }
+
+    Ok(res)
+}
+
+async fn get_transitions_controller(
+    Extension(pool): Extension<SqlitePool>,
+    jwt_auth: JWTAuth,
+    Query(filter): Query<TransitionFilter>,
+    Query(pagination): Query<Pagination>,
+) -> Result<Json<Vec<Transition>>, (StatusCode, String)> {
+    let res = get_transitions_service(pool, jwt_auth.id, filter, pagination)
+        .await
+        .map_err(|e| {
+            let err = e.to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, err)
+        })?;
+
+    Ok(Json(res))
+}
+
+async fn get_transitions_service(
+    pool: SqlitePool,
+    user_id: u32,
+    filter: TransitionFilter,
+    pagination: Pagination,
+) -> anyhow::Result<Vec<Transition>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT t.actuator_id, t.room_id, t.is_on, t.created_at
+            FROM actuator_transition t
+            INNER JOIN actuator a ON a.actuator_id = t.actuator_id
+            WHERE a.owner_id = ?
+            AND (? IS NULL OR t.actuator_id = ?)
+            ORDER BY t.transition_id DESC
+            LIMIT COALESCE(?, -1)
+            OFFSET COALESCE(?, 0)
+        "#,
+        user_id,
+        filter.actuator_id,
+        filter.actuator_id,
+        pagination.take,
+        pagination.skip,
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let mut res = vec![];
+
+    for row in rows {
+        res.push(Transition {
+            actuator_id: row.actuator_id,
+            room_id: row.room_id,
+            is_on: row.is_on,
+            created_at: row.created_at.to_string(),
+        });
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR_MS: i64 = 3_600_000;
+
+    #[test]
+    fn keeps_period_within_a_day() {
+        assert_eq!(split_days(DAY_MS + HOUR_MS, DAY_MS + 3 * HOUR_MS, 0), vec![(1, 2 * HOUR_MS)]);
+    }
+
+    #[test]
+    fn splits_at_midnight() {
+        assert_eq!(
+            split_days(DAY_MS - HOUR_MS, 3 * DAY_MS + HOUR_MS, 0),
+            vec![(0, HOUR_MS), (1, DAY_MS), (2, DAY_MS), (3, HOUR_MS)]
+        );
+    }
+
+    #[test]
+    fn splits_at_local_midnight() {
+        // 23:00 UTC is already the next day at UTC+2.
+        assert_eq!(
+            split_days(DAY_MS - 3 * HOUR_MS, DAY_MS - HOUR_MS, 2 * HOUR_MS),
+            vec![(0, HOUR_MS), (1, HOUR_MS)]
+        );
+        assert_eq!(
+            split_days(DAY_MS + HOUR_MS, DAY_MS + 3 * HOUR_MS, -2 * HOUR_MS),
+            vec![(0, HOUR_MS), (1, HOUR_MS)]
+        );
+    }
+
+    #[test]
+    fn empty_period() {
+        assert!(split_days(DAY_MS, DAY_MS, 0).is_empty());
+        assert!(split_days(DAY_MS, 0, 0).is_empty());
+    }
+}
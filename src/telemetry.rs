@@ -0,0 +1,154 @@
+use crate::actuator;
+use crate::clock;
+use crate::codec::Codec;
+use crate::diagnostics;
+use crate::esp_websockets::{
+    save_hello, settings, WsInnerData, WsInputData, WsOutputData, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use crate::firmware;
+use crate::hub::DeviceHub;
+use crate::ingest::{self, InvalidReading};
+use crate::utils::device_token::{verify_token, DeviceToken};
+use axum::body::Bytes;
+use axum::extract::Path;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use http::header::CONTENT_TYPE;
+use sqlx::SqlitePool;
+use std::collections::HashSet;
+
+/// For devices that cannot hold a WebSocket open, e.g. deep-sleep sensors that
+/// wake, post one message and sleep again. Accepts the same messages as the
+/// socket, in the codec named by `Content-Type`, and responds with everything
+/// the device would have been sent on connect so it can apply it before
+/// sleeping.
+pub async fn telemetry_controller(
+    Extension(pool): Extension<SqlitePool>,
+    Extension(hub): Extension<DeviceHub>,
+    DeviceToken(token): DeviceToken,
+    Path(device_id): Path<i64>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, (StatusCode, String)> {
+    let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    let codec = Codec::from_content_type(content_type);
+    let res = handle_telemetry(&pool, &hub, device_id, token.as_deref(), codec, &body).await?;
+
+    let body = codec
+        .encode(&res)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(([(CONTENT_TYPE, codec.content_type())], body).into_response())
+}
+
+/// Authenticates and ingests a single message posted outside of a WebSocket.
+/// Shared by every request/response transport. Messages that could not be
+/// stored fail with a server error, so the device keeps them for its next
+/// attempt.
+pub async fn handle_telemetry(
+    pool: &SqlitePool,
+    hub: &DeviceHub,
+    device_id: i64,
+    token: Option<&str>,
+    codec: Codec,
+    body: &[u8],
+) -> Result<Vec<WsOutputData>, (StatusCode, String)> {
+    authenticate(pool, device_id, token).await?;
+
+    let data = match codec.decode::<WsInputData>(body) {
+        Ok(data) if data.device_id == device_id => data,
+        _ => {
+            _ = diagnostics::record_malformed_frame(pool, device_id).await;
+            return Err((StatusCode::BAD_REQUEST, "Malformed message".to_string()));
+        }
+    };
+
+    let mut res = vec![];
+    match data.inner {
+        WsInnerData::Hello(hello) => {
+            if hello.protocol_version < MIN_PROTOCOL_VERSION {
+                let reason = format!(
+                    "Protocol version {} is not supported, minimum is {}",
+                    hello.protocol_version, MIN_PROTOCOL_VERSION
+                );
+                return Err((StatusCode::BAD_REQUEST, reason));
+            }
+
+            let protocol_version = hello.protocol_version.min(PROTOCOL_VERSION);
+            save_hello(pool, device_id, protocol_version, &hello)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            _ = firmware::on_hello(pool, device_id, &hello.firmware_version).await;
+            res.push(WsOutputData::Welcome {
+                protocol_version,
+                codec,
+            });
+        }
+        _ => {
+            ingest::process_input(pool, hub, device_id, data).await.map_err(|e| {
+                let status = if e.is::<InvalidReading>() {
+                    StatusCode::BAD_REQUEST
+                } else {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                };
+                (status, e.to_string())
+            })?;
+        }
+    }
+
+    res.extend(pending_messages(pool, device_id).await);
+    Ok(res)
+}
+
+/// Checks the device's credentials. Unknown devices have to be claimed over
+/// the WebSocket first.
+pub async fn authenticate(
+    pool: &SqlitePool,
+    device_id: i64,
+    token: Option<&str>,
+) -> Result<(), (StatusCode, String)> {
+    let device = sqlx::query!("SELECT secret_hash FROM device WHERE device_id = ?", device_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "Device not found".to_string()))?;
+    if !verify_token(token, device.secret_hash.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid device credentials".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Everything a device would have been sent on connect, limited to what its
+/// last hello said it can handle.
+pub async fn pending_messages(pool: &SqlitePool, device_id: i64) -> Vec<WsOutputData> {
+    let mut res = vec![settings(), clock::time_message(pool, device_id).await];
+    res.extend(actuator::pending_commands(pool, device_id).await.unwrap_or_default());
+    if let Ok(Some(update)) = firmware::pending_update(pool, device_id).await {
+        res.push(update);
+    }
+
+    let capabilities = capabilities(pool, device_id).await;
+    res.retain(|msg| match msg.required_capability() {
+        Some(capability) => capabilities.contains(capability),
+        None => true,
+    });
+
+    res
+}
+
+/// Capabilities from the device's last hello.
+pub async fn capabilities(pool: &SqlitePool, device_id: i64) -> HashSet<String> {
+    let capabilities = sqlx::query_scalar!(
+        "SELECT capabilities FROM device_info WHERE device_id = ?",
+        device_id
+    )
+    .fetch_optional(pool)
+    .await;
+
+    match capabilities {
+        Ok(Some(capabilities)) => serde_json::from_str(&capabilities).unwrap_or_default(),
+        _ => HashSet::new(),
+    }
+}
@@ -0,0 +1,10 @@
+use std::str::FromStr;
+
+/// Reads `key` from the environment and parses it, falling back to `default`
+/// when the variable is missing or malformed.
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    std::env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
@@ -0,0 +1,46 @@
+use rand::distributions::{Alphanumeric, DistString};
+use rand::seq::SliceRandom;
+use sha2::{Digest, Sha256};
+
+/// Credential presented by a device, either as `Authorization: Bearer` or as
+/// the `token` query parameter.
+#[derive(Clone)]
+pub struct DeviceToken(pub Option<String>);
+
+/// Letters and digits that cannot be confused on a small display.
+const CLAIM_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CLAIM_CODE_LEN: usize = 6;
+
+pub fn generate_token() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 32)
+}
+
+pub fn generate_claim_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CLAIM_CODE_LEN)
+        .map(|_| *CLAIM_CODE_ALPHABET.choose(&mut rng).unwrap() as char)
+        .collect()
+}
+
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Devices registered before credentials existed have no hash and are
+/// refused until their owner claims them again.
+pub fn verify_token(token: Option<&str>, hash: Option<&str>) -> bool {
+    match (token, hash) {
+        (Some(token), Some(hash)) => constant_time_eq(hash_token(token).as_bytes(), hash.as_bytes()),
+        _ => false,
+    }
+}
+
+/// Compares without returning early, so the time taken does not reveal how
+/// much of the hash matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,4 +1,6 @@
+pub mod config;
+pub mod device_token;
 pub mod jwt;
 mod pagination;
 
-pub use pagination::Pagination;
\ No newline at end of file
+pub use pagination::Pagination;
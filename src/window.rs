@@ -0,0 +1,120 @@
+use crate::events::{self, DeviceEvent};
+use crate::heating::{self, PAUSED_BY_WINDOW};
+use crate::hub::DeviceHub;
+use crate::ingest::now_ms;
+use crate::utils::config::env_or;
+use sqlx::SqlitePool;
+
+/// Looks for a sharp drop in the device's recent temperature readings, which
+/// without a contact sensor usually means a window was opened. Heating of the
+/// room is then paused for the room's `window_open_pause_minutes`.
+pub async fn check(pool: &SqlitePool, hub: &DeviceHub, device_id: i64) -> anyhow::Result<()> {
+    let room = sqlx::query!(
+        r#"
+        SELECT r.room_id, r.window_open_pause_minutes
+            FROM room r
+            INNER JOIN device d ON d.room_id = r.room_id
+            WHERE d.device_id = ? AND r.window_open_at IS NULL AND r.window_open_pause_minutes > 0
+        "#,
+        device_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    let Some(room) = room else {
+        return Ok(());
+    };
+
+    let since = (now_ms() - env_or("WINDOW_OPEN_WINDOW_MS", 600000i64)) / 1000;
+    let readings = sqlx::query!(
+        r#"
+        SELECT CAST(strftime('%s', created_at) AS INTEGER) AS "ts!: i64", temperature
+            FROM room_history
+            WHERE device_id = ? AND room_id = ? AND created_at >= datetime(?, 'unixepoch')
+            ORDER BY created_at
+        "#,
+        device_id,
+        room.room_id,
+        since,
+    )
+    .fetch_all(pool)
+    .await?;
+    let readings: Vec<_> = readings.into_iter().map(|r| (r.ts, r.temperature)).collect();
+    let rate = match drop_rate(&readings) {
+        Some(rate) if rate >= env_or("WINDOW_OPEN_DROP_PER_MINUTE", 0.2f64) => rate,
+        _ => return Ok(()),
+    };
+
+    let res = sqlx::query!(
+        "UPDATE room SET window_open_at = datetime('now') WHERE room_id = ? AND window_open_at IS NULL",
+        room.room_id
+    )
+    .execute(pool)
+    .await?;
+    if res.rows_affected() == 0 {
+        return Ok(());
+    }
+
+    println!("Detected open window in room {} ({:.2} °C/min)", room.room_id, rate);
+    events::emit(DeviceEvent::WindowOpen {
+        device_id,
+        room_id: room.room_id,
+    });
+    let until = now_ms() / 1000 + room.window_open_pause_minutes * 60;
+    heating::pause(pool, hub, room.room_id, PAUSED_BY_WINDOW, Some(until)).await
+}
+
+/// Clears the open window of rooms whose pause is over. Heating itself
+/// resumes through the pause's end time.
+pub async fn close_expired(pool: &SqlitePool) -> anyhow::Result<()> {
+    let rooms = sqlx::query_scalar!(
+        r#"
+        UPDATE room SET window_open_at = NULL
+            WHERE window_open_at <= datetime('now', '-' || window_open_pause_minutes || ' minutes')
+            RETURNING room_id AS "room_id!"
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for room_id in rooms {
+        events::emit(DeviceEvent::WindowClosed { room_id });
+    }
+
+    Ok(())
+}
+
+/// Drop from the highest reading to the latest one in °C per minute. Drops
+/// smaller than `WINDOW_OPEN_MIN_DROP` are sensor noise and give `None`.
+fn drop_rate(readings: &[(i64, f64)]) -> Option<f64> {
+    let (latest_ts, latest) = *readings.last()?;
+    let (peak_ts, peak) = readings.iter().copied().max_by(|a, b| a.1.total_cmp(&b.1))?;
+    if peak - latest < env_or("WINDOW_OPEN_MIN_DROP", 0.5f64) || latest_ts <= peak_ts {
+        return None;
+    }
+
+    Some((peak - latest) / ((latest_ts - peak_ts) as f64 / 60.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_drop_from_peak() {
+        let readings = [(0, 21.0), (60, 22.0), (120, 21.5), (180, 20.0)];
+        assert_eq!(drop_rate(&readings), Some(1.0));
+    }
+
+    #[test]
+    fn ignores_noise_and_rises() {
+        assert_eq!(drop_rate(&[(0, 21.0), (60, 20.8)]), None);
+        assert_eq!(drop_rate(&[(0, 20.0), (60, 22.0)]), None);
+        assert_eq!(drop_rate(&[(0, 21.0)]), None);
+        assert_eq!(drop_rate(&[]), None);
+    }
+
+    #[test]
+    fn needs_time_between_peak_and_latest() {
+        assert_eq!(drop_rate(&[(60, 22.0), (60, 20.0)]), None);
+    }
+}